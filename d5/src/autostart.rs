@@ -0,0 +1,390 @@
+//! XDG autostart support
+//!
+//! Scans the autostart directories for `.desktop` files, filters them like the
+//! [Desktop Application Autostart Specification](https://specifications.freedesktop.org/autostart-spec/autostart-spec-latest.html)
+//! says, and launches them once the session services are up.
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use directories::BaseDirs;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tracing::{debug, info, warn};
+use zbus_systemd::systemd1::ManagerProxy as SystemdManagerProxy;
+
+use crate::config::LaunchBackend;
+use crate::scope::AppScope;
+use crate::util::find_executable;
+
+/// `X-GNOME-Autostart-Phase`, in the order the phases are started in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    EarlyInitialization,
+    PreDisplayServer,
+    DisplayServer,
+    Initialization,
+    WindowManager,
+    Panel,
+    Desktop,
+    Applications,
+}
+
+impl Phase {
+    fn parse(phase: &str) -> Option<Self> {
+        Some(match phase {
+            "EarlyInitialization" => Self::EarlyInitialization,
+            "PreDisplayServer" => Self::PreDisplayServer,
+            "DisplayServer" => Self::DisplayServer,
+            "Initialization" => Self::Initialization,
+            "WindowManager" => Self::WindowManager,
+            "Panel" => Self::Panel,
+            "Desktop" => Self::Desktop,
+            "Applications" => Self::Applications,
+            _ => return None,
+        })
+    }
+}
+
+/// The parts of a `.desktop` file that matter for autostart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesktopEntry {
+    /// Desktop file ID, the file name without `.desktop`
+    pub id: String,
+    pub path: PathBuf,
+    pub name: Option<String>,
+    pub exec: Option<String>,
+    pub try_exec: Option<String>,
+    pub hidden: bool,
+    pub only_show_in: Vec<String>,
+    pub not_show_in: Vec<String>,
+    pub phase: Phase,
+    pub gnome_autostart_enabled: bool,
+}
+
+impl DesktopEntry {
+    /// Parse the `[Desktop Entry]` group of a desktop file
+    pub fn parse(id: &str, path: &Path, contents: &str) -> Result<Self> {
+        let mut keys = HashMap::new();
+        let mut group = None;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                group = Some(name);
+                continue;
+            }
+            if group != Some("Desktop Entry") {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            // localised keys (Name[de]) are not needed for launching
+            keys.entry(key.trim_end())
+                .or_insert_with(|| unescape(value.trim_start()));
+        }
+        if group.is_none() {
+            return Err(eyre!("{} has no [Desktop Entry] group", path.display()));
+        }
+
+        let boolean = |key: &str, default: bool| match keys.get(key).map(String::as_str) {
+            Some("true") => true,
+            Some("false") => false,
+            _ => default,
+        };
+        let list = |key: &str| {
+            keys.get(key)
+                .map(|v| {
+                    v.split(';')
+                        .filter(|s| !s.is_empty())
+                        .map(str::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        Ok(Self {
+            id: id.to_owned(),
+            path: path.to_owned(),
+            name: keys.get("Name").cloned(),
+            exec: keys.get("Exec").cloned(),
+            try_exec: keys.get("TryExec").cloned(),
+            hidden: boolean("Hidden", false),
+            only_show_in: list("OnlyShowIn"),
+            not_show_in: list("NotShowIn"),
+            phase: keys
+                .get("X-GNOME-Autostart-Phase")
+                .and_then(|p| Phase::parse(p))
+                .unwrap_or(Phase::Applications),
+            gnome_autostart_enabled: boolean("X-GNOME-Autostart-enabled", true),
+        })
+    }
+
+    /// Whether this entry should be started in a session running the given desktops
+    pub fn should_start(&self, desktops: &[String]) -> bool {
+        if self.hidden || !self.gnome_autostart_enabled || self.exec.is_none() {
+            return false;
+        }
+        if !self.only_show_in.is_empty() && !self.only_show_in.iter().any(|d| desktops.contains(d))
+        {
+            return false;
+        }
+        if self.not_show_in.iter().any(|d| desktops.contains(d)) {
+            return false;
+        }
+        match &self.try_exec {
+            Some(try_exec) => find_executable(try_exec).is_some(),
+            None => true,
+        }
+    }
+
+    /// The command line to run, with field codes removed
+    pub fn command(&self) -> Result<Vec<String>> {
+        let exec = self
+            .exec
+            .as_deref()
+            .ok_or_else(|| eyre!("{} has no Exec key", self.path.display()))?;
+        let args = shell_words::split(exec)?
+            .into_iter()
+            .filter_map(|arg| match arg.as_str() {
+                // there are no files or URLs to pass at autostart
+                "%f" | "%F" | "%u" | "%U" | "%d" | "%D" | "%n" | "%N" | "%i" | "%c" | "%k"
+                | "%v" | "%m" => None,
+                _ => Some(arg.replace("%%", "%")),
+            })
+            .collect::<Vec<_>>();
+        if args.is_empty() {
+            return Err(eyre!("{} has an empty Exec key", self.path.display()));
+        }
+        Ok(args)
+    }
+}
+
+/// Undo the escapes allowed in desktop file string values
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => out.push(' '),
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// The autostart directories, most important first
+pub fn autostart_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if let Some(base) = BaseDirs::new() {
+        dirs.push(base.config_dir().join("autostart"));
+    }
+    let config_dirs = std::env::var("XDG_CONFIG_DIRS")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| "/etc/xdg".to_owned());
+    dirs.extend(
+        std::env::split_paths(&config_dirs)
+            .filter(|d| d.is_absolute())
+            .map(|d| d.join("autostart")),
+    );
+    dirs
+}
+
+/// The desktops named in `XDG_CURRENT_DESKTOP`
+pub fn current_desktops() -> Vec<String> {
+    std::env::var("XDG_CURRENT_DESKTOP")
+        .map(|d| {
+            d.split(':')
+                .filter(|s| !s.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Collect every autostart entry from `dirs`, earlier directories shadowing later ones
+///
+/// Hidden and filtered entries still shadow entries of the same ID, that is how users disable system entries.
+/// The result is sorted by phase.
+pub fn scan(dirs: &[PathBuf], desktops: &[String]) -> Vec<DesktopEntry> {
+    let mut entries: BTreeMap<String, DesktopEntry> = BTreeMap::new();
+    for dir in dirs {
+        let Ok(files) = dir.read_dir() else {
+            continue;
+        };
+        for file in files.flatten() {
+            let path = file.path();
+            if path.extension() != Some(OsStr::new("desktop")) {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if entries.contains_key(id) {
+                debug!("{} is shadowed", path.display());
+                continue;
+            }
+            let entry = std::fs::read_to_string(&path)
+                .map_err(Into::into)
+                .and_then(|contents| DesktopEntry::parse(id, &path, &contents));
+            match entry {
+                Ok(entry) => {
+                    entries.insert(id.to_owned(), entry);
+                }
+                Err(e) => warn!("Failed to load autostart entry {}: {:?}", path.display(), e),
+            }
+        }
+    }
+
+    let mut entries = entries
+        .into_values()
+        .filter(|e| e.should_start(desktops))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|e| e.phase);
+    entries
+}
+
+/// A launched autostart entry
+pub enum Launched {
+    Script(Child),
+    Scope(AppScope),
+}
+
+impl Launched {
+    /// Stop the app, killing a script app that is still around after `timeout`
+    pub async fn stop(self, systemd: &SystemdManagerProxy<'_>, timeout: Duration) -> Result<()> {
+        match self {
+            Launched::Script(mut child) => {
+                if child.try_wait()?.is_some() {
+                    return Ok(());
+                }
+                if let Some(pid) = child.id() {
+                    kill(Pid::from_raw(pid as i32), Signal::SIGTERM)?;
+                }
+                if tokio::time::timeout(timeout, child.wait()).await.is_err() {
+                    debug!(pid = ?child.id(), "Autostart app did not exit in time, killing it");
+                    child.kill().await?;
+                }
+            }
+            Launched::Scope(scope) => scope.stop(systemd, timeout).await?,
+        }
+        Ok(())
+    }
+}
+
+/// Launch a single entry with the given backend
+pub async fn launch(
+    systemd: &SystemdManagerProxy<'_>,
+    backend: LaunchBackend,
+    entry: &DesktopEntry,
+) -> Result<Launched> {
+    match backend {
+        LaunchBackend::Script => {
+            let cmd = entry.command()?;
+            let (cmd, args) = cmd.split_first().unwrap();
+            Ok(Launched::Script(Command::new(cmd).args(args).spawn()?))
+        }
+        LaunchBackend::Systemd => {
+            let description = entry.name.as_deref().unwrap_or(&entry.id);
+            let scope =
+                AppScope::launch(systemd, &entry.id, description, &entry.command()?).await?;
+            Ok(Launched::Scope(scope))
+        }
+    }
+}
+
+/// Launch every autostart entry for this session
+pub async fn start_autostart(
+    systemd: &SystemdManagerProxy<'_>,
+    backend: LaunchBackend,
+) -> Vec<Launched> {
+    let entries = scan(&autostart_dirs(), &current_desktops());
+    let mut launched = Vec::with_capacity(entries.len());
+    for entry in entries {
+        match launch(systemd, backend, &entry).await {
+            Ok(l) => {
+                info!("Autostarted {}", entry.id);
+                launched.push(l);
+            }
+            Err(e) => warn!("Failed to autostart {}: {:?}", entry.id, e),
+        }
+    }
+    launched
+}
+
+#[cfg(test)]
+fn fixture_dirs() -> Vec<PathBuf> {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/autostart");
+    vec![fixtures.join("home"), fixtures.join("system")]
+}
+
+#[test]
+fn user_entries_shadow_system_entries() {
+    let entries = scan(&fixture_dirs(), &["Kiri".to_owned()]);
+    let ids = entries.iter().map(|e| e.id.as_str()).collect::<Vec<_>>();
+    // disabled-by-user is Hidden in home, shadowed-app is overridden by home
+    assert!(!ids.contains(&"disabled-by-user"));
+    let shadowed = entries.iter().find(|e| e.id == "shadowed-app").unwrap();
+    assert_eq!(shadowed.exec.as_deref(), Some("true --from-home"));
+}
+
+#[test]
+fn show_in_filters_by_current_desktop() {
+    let kiri = scan(&fixture_dirs(), &["Kiri".to_owned()]);
+    let gnome = scan(&fixture_dirs(), &["GNOME".to_owned()]);
+    let has = |entries: &[DesktopEntry], id: &str| entries.iter().any(|e| e.id == id);
+
+    assert!(has(&kiri, "kiri-only"));
+    assert!(!has(&gnome, "kiri-only"));
+    assert!(!has(&kiri, "not-in-kiri"));
+    assert!(has(&gnome, "not-in-kiri"));
+}
+
+#[test]
+fn try_exec_and_gnome_enabled_are_honoured() {
+    let ids = scan(&fixture_dirs(), &[])
+        .into_iter()
+        .map(|e| e.id)
+        .collect::<Vec<_>>();
+    assert!(!ids.contains(&"missing-try-exec".to_owned()));
+    assert!(!ids.contains(&"gnome-disabled".to_owned()));
+}
+
+#[test]
+fn entries_are_sorted_by_phase() {
+    let entries = scan(&fixture_dirs(), &[]);
+    assert_eq!(entries.first().unwrap().id, "early-phase");
+    assert!(entries.windows(2).all(|w| w[0].phase <= w[1].phase));
+}
+
+#[test]
+fn exec_field_codes_are_removed() {
+    let entry = DesktopEntry::parse(
+        "viewer",
+        Path::new("viewer.desktop"),
+        "[Desktop Entry]\nName=Viewer\nName[de]=Betrachter\nExec=\"my viewer\" --open %U 100%%\n",
+    )
+    .unwrap();
+    assert_eq!(entry.name.as_deref(), Some("Viewer"));
+    assert_eq!(entry.command().unwrap(), ["my viewer", "--open", "100%"]);
+}
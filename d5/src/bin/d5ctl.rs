@@ -0,0 +1,291 @@
+//! d5ctl - control the running d5 session manager
+//! Talks to `com.fyralabs.d5` on the session bus.
+
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde_json::json;
+use std::collections::HashMap;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{dbus_proxy, Connection};
+
+/// Cookie, app ID, reason, flags and bus name
+type Inhibitor = (u32, String, String, u32, String);
+
+#[dbus_proxy(
+    interface = "com.fyralabs.d5",
+    default_service = "com.fyralabs.d5",
+    default_path = "/com/fyralabs/d5"
+)]
+trait D5 {
+    fn logout(&self) -> zbus::Result<()>;
+    fn power_off(&self) -> zbus::Result<()>;
+    fn get_environment(&self) -> zbus::Result<HashMap<String, String>>;
+    fn list_inhibitors(&self) -> zbus::Result<Vec<Inhibitor>>;
+    fn list_services(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+    #[dbus_proxy(property)]
+    fn session_state(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn can_power_off(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn can_reboot(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn can_suspend(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn can_hibernate(&self) -> zbus::Result<String>;
+}
+
+#[dbus_proxy(
+    interface = "com.fyralabs.d5.Service",
+    default_service = "com.fyralabs.d5"
+)]
+trait Service {
+    fn restart(&self) -> zbus::Result<()>;
+    #[dbus_proxy(property)]
+    fn name(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property, name = "Type")]
+    fn service_type(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn state(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn pid(&self) -> zbus::Result<u32>;
+    #[dbus_proxy(property)]
+    fn unit(&self) -> zbus::Result<String>;
+    #[dbus_proxy(property)]
+    fn restarts(&self) -> zbus::Result<u32>;
+    #[dbus_proxy(property)]
+    fn last_exit_code(&self) -> zbus::Result<i32>;
+    #[dbus_proxy(property)]
+    fn status_text(&self) -> zbus::Result<String>;
+}
+
+#[derive(Parser)]
+#[command(author, version, about = "Control the running d5 session")]
+struct D5ctl {
+    /// Print machine-readable JSON
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the state of the session
+    Status,
+    /// List session services
+    Services,
+    /// Restart a session service
+    Restart { service: String },
+    /// End the session
+    Logout,
+    /// End the session and power off
+    Poweroff,
+    /// List what is inhibiting logout, user switching, suspend or idle
+    Inhibitors,
+    /// Show the environment services and apps are started with
+    Env,
+}
+
+/// Names of the inhibitor flags, in bit order
+const INHIBIT_FLAGS: &[&str] = &["logout", "switch-user", "suspend", "idle"];
+
+fn flag_names(flags: u32) -> Vec<&'static str> {
+    INHIBIT_FLAGS
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| flags & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Proxies for every service object d5 serves
+async fn services(d5: &D5Proxy<'_>) -> Result<Vec<ServiceProxy<'static>>> {
+    let mut services = vec![];
+    for path in d5.list_services().await? {
+        services.push(
+            ServiceProxy::builder(d5.connection())
+                .path(path)?
+                .build()
+                .await?,
+        );
+    }
+    Ok(services)
+}
+
+async fn find_service(d5: &D5Proxy<'_>, name: &str) -> Result<ServiceProxy<'static>> {
+    for service in services(d5).await? {
+        if service.name().await? == name {
+            return Ok(service);
+        }
+    }
+    Err(eyre!("there is no service `{name}`"))
+}
+
+async fn status(d5: &D5Proxy<'_>, json: bool) -> Result<()> {
+    let state = d5.session_state().await?;
+    let power = [
+        ("poweroff", d5.can_power_off().await?),
+        ("reboot", d5.can_reboot().await?),
+        ("suspend", d5.can_suspend().await?),
+        ("hibernate", d5.can_hibernate().await?),
+    ];
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for service in services(d5).await? {
+        *counts.entry(service.state().await?).or_default() += 1;
+    }
+    let inhibitors = d5.list_inhibitors().await?.len();
+
+    if json {
+        let power: HashMap<_, _> = power.into_iter().collect();
+        println!(
+            "{}",
+            json!({
+                "state": state,
+                "power": power,
+                "services": counts,
+                "inhibitors": inhibitors,
+            })
+        );
+        return Ok(());
+    }
+
+    println!("State:      {state}");
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort();
+    let counts = counts
+        .iter()
+        .map(|(state, count)| format!("{count} {state}"))
+        .collect::<Vec<_>>();
+    println!("Services:   {}", counts.join(", "));
+    println!("Inhibitors: {inhibitors}");
+    for (action, answer) in power {
+        println!("Can {action}: {answer}");
+    }
+    Ok(())
+}
+
+async fn list_services(d5: &D5Proxy<'_>, json: bool) -> Result<()> {
+    let mut rows = vec![];
+    for service in services(d5).await? {
+        rows.push((
+            service.name().await?,
+            service.service_type().await?,
+            service.state().await?,
+            service.pid().await?,
+            service.unit().await?,
+            service.restarts().await?,
+            service.last_exit_code().await?,
+            service.status_text().await?,
+        ));
+    }
+    rows.sort();
+
+    if json {
+        let rows = rows
+            .into_iter()
+            .map(|(name, kind, state, pid, unit, restarts, exit, status)| {
+                json!({
+                    "name": name,
+                    "type": kind,
+                    "state": state,
+                    "pid": (pid != 0).then_some(pid),
+                    "unit": (!unit.is_empty()).then_some(unit),
+                    "restarts": restarts,
+                    "last_exit_code": (exit >= 0).then_some(exit),
+                    "status": (!status.is_empty()).then_some(status),
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::Value::Array(rows));
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:<8} {:<11} {:>8} {:>8} {:>9}  STATUS",
+        "NAME", "TYPE", "STATE", "PID", "RESTARTS", "LAST EXIT"
+    );
+    for (name, kind, state, pid, _, restarts, exit, status) in rows {
+        let pid = if pid == 0 {
+            "-".to_owned()
+        } else {
+            pid.to_string()
+        };
+        let exit = if exit < 0 {
+            "-".to_owned()
+        } else {
+            exit.to_string()
+        };
+        println!("{name:<24} {kind:<8} {state:<11} {pid:>8} {restarts:>8} {exit:>9}  {status}");
+    }
+    Ok(())
+}
+
+async fn inhibitors(d5: &D5Proxy<'_>, json: bool) -> Result<()> {
+    let inhibitors = d5.list_inhibitors().await?;
+
+    if json {
+        let rows = inhibitors
+            .into_iter()
+            .map(|(cookie, app_id, reason, flags, sender)| {
+                json!({
+                    "cookie": cookie,
+                    "app_id": app_id,
+                    "reason": reason,
+                    "flags": flag_names(flags),
+                    "sender": sender,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::Value::Array(rows));
+        return Ok(());
+    }
+
+    if inhibitors.is_empty() {
+        println!("Nothing is inhibited");
+        return Ok(());
+    }
+    for (cookie, app_id, reason, flags, sender) in inhibitors {
+        println!(
+            "{cookie}: {app_id} ({sender}) inhibits {}: {reason}",
+            flag_names(flags).join(", ")
+        );
+    }
+    Ok(())
+}
+
+async fn env(d5: &D5Proxy<'_>, json: bool) -> Result<()> {
+    let env = d5.get_environment().await?;
+    if json {
+        println!("{}", json!(env));
+        return Ok(());
+    }
+    let mut env = env.into_iter().collect::<Vec<_>>();
+    env.sort();
+    for (key, value) in env {
+        println!("{key}={value}");
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let args = D5ctl::parse();
+
+    let conn = Connection::session().await?;
+    let d5 = D5Proxy::new(&conn).await?;
+    match args.command {
+        Command::Status => status(&d5, args.json).await,
+        Command::Services => list_services(&d5, args.json).await,
+        Command::Restart { service } => {
+            find_service(&d5, &service).await?.restart().await?;
+            Ok(())
+        }
+        Command::Logout => Ok(d5.logout().await?),
+        Command::Poweroff => Ok(d5.power_off().await?),
+        Command::Inhibitors => inhibitors(&d5, args.json).await,
+        Command::Env => env(&d5, args.json).await,
+    }
+}
@@ -0,0 +1,160 @@
+//! `d5 check-config`
+//!
+//! Loads the merged session config and reports everything wrong with it, so packagers
+//! find out about broken session files before their users get bounced back to the greeter.
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use zbus_systemd::systemd1::{ManagerProxy as SystemdManagerProxy, UnitProxy};
+
+use crate::config::{Config, ConfigSource, ServiceType};
+use crate::util::find_executable;
+
+/// Check that the program of a command line can be found
+fn check_command(what: &str, command: &str, problems: &mut Vec<String>) {
+    // unparsable command lines are already reported by Config::problems
+    let Ok(words) = shell_words::split(command) else {
+        return;
+    };
+    match words.first() {
+        None => problems.push(format!("{what} has an empty command line")),
+        Some(program) if find_executable(program).is_none() => {
+            problems.push(format!("{what} runs `{program}`, which is not on PATH"))
+        }
+        Some(_) => {}
+    }
+}
+
+/// Ask the systemd user manager about every unit the config starts
+async fn check_units(config: &Config, problems: &mut Vec<String>) -> Result<()> {
+    let conn = zbus::Connection::session().await?;
+    let systemd = SystemdManagerProxy::new(&conn).await?;
+    for (name, service) in &config.services {
+        let Some(unit) = &service.unit else {
+            continue;
+        };
+        match load_state(&conn, &systemd, unit).await {
+            Ok(state) if state == "loaded" => {}
+            Ok(state) => problems.push(format!(
+                "service `{name}` uses unit `{unit}`, which is {state}"
+            )),
+            Err(e) => problems.push(format!(
+                "service `{name}` uses unit `{unit}`, which could not be loaded: {e}"
+            )),
+        }
+    }
+    Ok(())
+}
+
+async fn load_state(
+    conn: &zbus::Connection,
+    systemd: &SystemdManagerProxy<'_>,
+    unit: &str,
+) -> zbus::Result<String> {
+    let path = systemd.load_unit(unit.to_owned()).await?;
+    UnitProxy::builder(conn)
+        .path(path)?
+        .build()
+        .await?
+        .load_state()
+        .await
+}
+
+/// Everything wrong with the config that can be found without asking systemd
+fn problems(config: &Config, unknown: Vec<String>) -> Vec<String> {
+    let mut problems = unknown
+        .into_iter()
+        .map(|key| format!("unknown key `{key}`"))
+        .collect::<Vec<_>>();
+    problems.extend(config.problems());
+
+    check_command("the leader", &config.session.leader, &mut problems);
+    if let Some(fallback) = &config.session.fallback_leader {
+        check_command("the fallback leader", fallback, &mut problems);
+    }
+    if let Some(locker) = &config.session.locker {
+        check_command("the locker", locker, &mut problems);
+    }
+    if let Some(frontend) = &config.notifications.frontend {
+        check_command("the notification frontend", frontend, &mut problems);
+    }
+    for (i, idle) in config.idle.actions.iter().enumerate() {
+        if let Some(command) = &idle.command {
+            check_command(&format!("idle action {i}"), command, &mut problems);
+        }
+        if let Some(resume) = &idle.resume {
+            check_command(
+                &format!("the resume command of idle action {i}"),
+                resume,
+                &mut problems,
+            );
+        }
+    }
+    for (name, service) in &config.services {
+        if let (ServiceType::Script, Some(script)) = (service.service_type, &service.script) {
+            check_command(&format!("service `{name}`"), script, &mut problems);
+        }
+    }
+    problems
+}
+
+pub async fn check_config(source: &ConfigSource) -> Result<()> {
+    let (config, unknown) = crate::config::parse(crate::config::load_merged(source)?)?;
+
+    let mut problems = problems(&config, unknown);
+    if let Err(e) = check_units(&config, &mut problems).await {
+        eprintln!("warning: could not check systemd units: {e}");
+    }
+
+    for problem in &problems {
+        eprintln!("error: {problem}");
+    }
+    if !problems.is_empty() {
+        bail!("found {} problem(s) in the session config", problems.len());
+    }
+    println!("Session config is valid");
+    Ok(())
+}
+
+#[test]
+fn problems_are_collected() {
+    let value = toml::from_str(
+        r#"
+        [session]
+        leader = "d5-test-no-such-compositor --nested"
+        locker = "d5-test-no-such-locker"
+        fallback_leader = "d5-test-no-such-fallback"
+
+        [notifications]
+        frontend = "d5-test-no-such-frontend"
+
+        [[idle.actions]]
+        after = 300
+        command = "d5-test-no-such-dimmer --save"
+        resume = "d5-test-no-such-dimmer --restore"
+
+        [services.panel]
+        type = "script"
+        script = "sh -c 'exec kiri-panel'"
+        restrat = "always"
+
+        [services.keyring]
+        type = "systemd"
+        "#,
+    )
+    .unwrap();
+    let (config, unknown) = crate::config::parse(value).unwrap();
+    assert_eq!(
+        problems(&config, unknown),
+        [
+            "unknown key `services.panel.restrat`",
+            "service `keyring` is a systemd service but has no `unit`",
+            "the leader runs `d5-test-no-such-compositor`, which is not on PATH",
+            "the fallback leader runs `d5-test-no-such-fallback`, which is not on PATH",
+            "the locker runs `d5-test-no-such-locker`, which is not on PATH",
+            "the notification frontend runs `d5-test-no-such-frontend`, which is not on PATH",
+            "idle action 0 runs `d5-test-no-such-dimmer`, which is not on PATH",
+            "the resume command of idle action 0 runs `d5-test-no-such-dimmer`, which is not on PATH",
+        ]
+    );
+}
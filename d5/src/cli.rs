@@ -1,7 +1,11 @@
 //! CLI interface for d5
 
-use clap::{Parser, ValueEnum};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use color_eyre::Result;
+use std::path::PathBuf;
+
+use crate::config::ConfigSource;
 // enum for display mode
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum DisplayMode {
@@ -11,14 +15,21 @@ pub enum DisplayMode {
 
 #[derive(Parser)]
 pub struct D5Entrypoint {
-    // no subcommands, because it literally just launches a systemd user target
+    // without a subcommand, d5 launches the session
     // session manager is fun
     /// systemd target to launch
     // #[clap(short, long, required = true)]
     // pub target: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Session name, looked up in the d5 config directories
+    #[clap(short, long, global = true)]
+    pub session: Option<String>,
 
-    #[clap(short, long, required = true)]
-    pub session: String,
+    /// Load the session config from this file instead of searching for it
+    #[clap(long, global = true, conflicts_with = "session")]
+    pub config: Option<PathBuf>,
 
     /// Display mode: either "x11" or "wayland"
     #[clap(short, long, default_value = "x11")]
@@ -26,13 +37,49 @@ pub struct D5Entrypoint {
     pub display: DisplayMode,
 }
 
+#[derive(Subcommand)]
+pub enum Command {
+    /// Validate the session config and report every problem found
+    CheckConfig,
+    /// Print the effective session config, after merging every config file
+    ShowConfig,
+}
+
+impl D5Entrypoint {
+    /// Where to load the session config from
+    pub fn config_source(&self) -> ConfigSource {
+        match (&self.config, &self.session) {
+            (Some(path), _) => ConfigSource::File(path.clone()),
+            (None, Some(session)) => ConfigSource::Session(session.clone()),
+            // not required through clap, since global args cannot be required
+            (None, None) => D5Entrypoint::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "either --session or --config is required",
+                )
+                .exit(),
+        }
+    }
+}
+
 /// Parse the CLI arguments
 
 pub async fn entrypoint() -> Result<()> {
     let args = D5Entrypoint::parse();
-    crate::env::load_envs(args.display)?;
+    match args.command {
+        Some(Command::CheckConfig) => crate::check::check_config(&args.config_source()).await,
+        Some(Command::ShowConfig) => {
+            let config = crate::config::load_config(&args.config_source())?;
+            print!("{}", toml::to_string_pretty(&config)?);
+            Ok(())
+        }
+        None => {
+            crate::env::load_envs(args.display).await?;
 
-    let config = crate::config::load_config(&args.session)?;
-    crate::session::new_session(config).await?;
-    Ok(())
+            let source = args.config_source();
+            let config = crate::config::load_config(&source)?;
+            crate::session::new_session(config, source, args.display).await?;
+            Ok(())
+        }
+    }
 }
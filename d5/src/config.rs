@@ -4,23 +4,197 @@
 // Why don't we use systemd's target files? Because we cannot monitor them. We do not know when they are stopped.
 // We need to know when the session is stopped so we can kill d5.
 
-// The configuration file will be in TOML format. It will be located in /etc/d5.conf.d/ and will be named after the session name.
+// The configuration file will be in TOML format, named after the session name.
+// It is looked up in /usr/share/d5/sessions/ (vendor), /etc/d5.conf.d/ (admin) and $XDG_CONFIG_HOME/d5/ (user).
+// Every file that exists is deep-merged over the previous ones, so an override only needs the keys it changes.
 
+use color_eyre::eyre::{bail, eyre};
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub session: SessionConfig,
     #[serde(default)]
+    pub environment: EnvironmentConfig,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    #[serde(default)]
+    pub idle: IdleConfig,
+    #[serde(default)]
     pub services: BTreeMap<String, ServiceConfig>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct SessionConfig {
     /// The command to launch the leader process
     pub leader: String,
+    /// How many times to restart the leader after it crashes
+    #[serde(default)]
+    pub restart_leader: u32,
+    /// Leader to run instead when the leader fails right after starting, for a failsafe session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_leader: Option<String>,
+    /// Whether to launch XDG autostart entries
+    #[serde(default)]
+    pub autostart: bool,
+    /// How to launch XDG autostart entries
+    #[serde(default)]
+    pub xdg_autostart: LaunchBackend,
+    /// Variables exported to the systemd user manager and the D-Bus activation environment
+    #[serde(default = "default_export_env")]
+    pub export_env: Vec<String>,
+    /// Wayland socket name to give the leader, if unset d5 waits for whatever socket the leader creates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wayland_display: Option<String>,
+    /// Seconds to wait for the leader's Wayland socket before giving up on the session
+    #[serde(default = "default_wayland_timeout")]
+    pub wayland_timeout: u64,
+    /// Seconds clients and logout inhibitors get to let a logout through before it is called off
+    #[serde(default = "default_logout_timeout")]
+    pub logout_timeout: u64,
+    /// Seconds the leader, script services and autostart apps get to exit after SIGTERM before they are killed
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout: u64,
+    /// Command that locks the screen when logind asks the session to lock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locker: Option<String>,
+    /// Whether to lock the screen before the system suspends
+    #[serde(default = "default_lock_before_sleep")]
+    pub lock_before_sleep: bool,
+}
+
+fn default_wayland_timeout() -> u64 {
+    10
+}
+
+fn default_logout_timeout() -> u64 {
+    10
+}
+
+fn default_stop_timeout() -> u64 {
+    5
+}
+
+fn default_ready_timeout() -> u64 {
+    30
+}
+
+fn default_lock_before_sleep() -> bool {
+    true
+}
+
+fn default_export_env() -> Vec<String> {
+    crate::env::DEFAULT_EXPORT_ENV
+        .iter()
+        .map(|v| v.to_string())
+        .collect()
+}
+
+/// Variables the leader, services and apps get for this session
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct EnvironmentConfig {
+    /// Variables to remove
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unset: Vec<String>,
+    /// Variables to set, `${VAR}` and `${VAR:-default}` expand to d5's own environment
+    #[serde(flatten)]
+    pub vars: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct NotificationConfig {
+    /// Serve org.freedesktop.Notifications when no other notification daemon runs or can be activated
+    #[serde(default)]
+    pub server: bool,
+    /// Command that displays notifications, fed one JSON event per line on stdin
+    ///
+    /// Without one, frontends follow the signals on `com.fyralabs.d5.Notifications`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frontend: Option<String>,
+    #[serde(default)]
+    pub history: HistoryConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryConfig {
+    /// Whether to record notifications in `$XDG_STATE_HOME/d5/notifications`
+    #[serde(default = "default_history_enabled")]
+    pub enabled: bool,
+    /// How many notifications to keep, the oldest are dropped first
+    #[serde(default = "default_history_max_entries")]
+    pub max_entries: usize,
+    /// Days to keep notifications for, 0 keeps them until `max_entries` pushes them out
+    #[serde(default = "default_history_max_age_days")]
+    pub max_age_days: u32,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_history_enabled(),
+            max_entries: default_history_max_entries(),
+            max_age_days: default_history_max_age_days(),
+        }
+    }
+}
+
+fn default_history_enabled() -> bool {
+    true
+}
+
+fn default_history_max_entries() -> usize {
+    500
+}
+
+fn default_history_max_age_days() -> u32 {
+    30
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct IdleConfig {
+    /// What to do once the session has been idle for a while, run in order of `after`
+    #[serde(default)]
+    pub actions: Vec<IdleAction>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IdleAction {
+    /// Seconds the session has to be idle for
+    pub after: u64,
+    /// Built-in action to take
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<IdleBuiltin>,
+    /// Command to run, instead of a built-in action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// Command to run when the session is no longer idle, if this action was taken
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume: Option<String>,
+    /// Only take this action on battery power
+    #[serde(default)]
+    pub on_battery: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleBuiltin {
+    /// Lock the session through logind
+    Lock,
+    Suspend,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LaunchBackend {
+    /// Spawn programs as child processes of d5
+    #[default]
+    Script,
+    /// Launch programs in their own transient systemd scope
+    Systemd,
 }
 
 // services config would be:
@@ -28,25 +202,583 @@ pub struct SessionConfig {
 // [services.foo]
 // script = "echo foo"
 // type = "script"
+//
+// [services.bar]
+// unit = "bar.service"
+// type = "systemd"
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum ServiceType {
     Script,
     Systemd,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ServiceConfig {
-    /// The command to launch the service
-    pub command: String,
+    /// The systemd unit to start, for systemd services
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// The command line to run, for script services
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
     /// The type of service
     #[serde(rename = "type")]
     pub service_type: ServiceType,
+    /// Services that must be started before this one, if they are started at all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+    /// Services that must start successfully before this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<String>,
+    /// Services that should be started before this one, but are not required
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wants: Vec<String>,
+    /// When to restart a script service after it exits
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    /// End the session if this service fails and will not be restarted
+    #[serde(default)]
+    pub critical: bool,
+    /// When the service counts as started, for the services after it
+    #[serde(default)]
+    pub ready: Readiness,
+    /// Seconds the service gets to become ready before it counts as failed
+    #[serde(default = "default_ready_timeout")]
+    pub ready_timeout: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Never restart the service
+    #[default]
+    No,
+    /// Restart the service if it exits unsuccessfully
+    OnFailure,
+    /// Restart the service whenever it exits
+    Always,
+}
+
+impl Config {
+    /// Variables to push into the systemd user manager and the D-Bus activation environment
+    ///
+    /// These are `export_env` and everything the `[environment]` table sets or unsets.
+    pub fn export_env(&self) -> Vec<String> {
+        let mut names = self.session.export_env.clone();
+        for name in self.environment.vars.keys().chain(&self.environment.unset) {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+}
+
+/// When a service counts as started: `"started"`, `"notify"` or `"dbus:<name>"`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum Readiness {
+    /// Once the script is spawned, or the systemd unit's start job is done
+    #[default]
+    Started,
+    /// Once the script sends `READY=1` to `$NOTIFY_SOCKET`, like `Type=notify` units
+    Notify,
+    /// Once the bus name is owned on the session bus
+    DBus(String),
+}
+
+impl TryFrom<String> for Readiness {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        match value.as_str() {
+            "started" => Ok(Self::Started),
+            "notify" => Ok(Self::Notify),
+            _ => match value.strip_prefix("dbus:") {
+                Some(name) => Ok(Self::DBus(name.to_owned())),
+                None => Err(format!(
+                    "unknown readiness `{value}`, expected \"started\", \"notify\" or \"dbus:<name>\""
+                )),
+            },
+        }
+    }
+}
+
+impl From<Readiness> for String {
+    fn from(ready: Readiness) -> Self {
+        match ready {
+            Readiness::Started => "started".to_owned(),
+            Readiness::Notify => "notify".to_owned(),
+            Readiness::DBus(name) => format!("dbus:{name}"),
+        }
+    }
+}
+
+impl ServiceConfig {
+    /// Every service this one is ordered after
+    pub fn dependencies(&self) -> impl Iterator<Item = &str> {
+        self.after
+            .iter()
+            .chain(&self.requires)
+            .chain(&self.wants)
+            .map(String::as_str)
+    }
+}
+
+impl Config {
+    /// Everything wrong with the config that can be found without looking at the system
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if let Err(e) = shell_words::split(&self.session.leader) {
+            problems.push(format!("the leader command line is invalid: {e}"));
+        }
+        if let Some(Err(e)) = self
+            .session
+            .fallback_leader
+            .as_deref()
+            .map(shell_words::split)
+        {
+            problems.push(format!("the fallback leader command line is invalid: {e}"));
+        }
+        if let Some(Err(e)) = self.session.locker.as_deref().map(shell_words::split) {
+            problems.push(format!("the locker command line is invalid: {e}"));
+        }
+        if let Some(Err(e)) = self
+            .notifications
+            .frontend
+            .as_deref()
+            .map(shell_words::split)
+        {
+            problems.push(format!(
+                "the notification frontend command line is invalid: {e}"
+            ));
+        }
+        for name in self.environment.vars.keys().chain(&self.environment.unset) {
+            if name.is_empty() || name.contains('=') {
+                problems.push(format!("`{name}` is not a valid environment variable name"));
+            }
+        }
+        for (name, value) in &self.environment.vars {
+            if let Err(e) = crate::env::expand(value, |_| None) {
+                problems.push(format!("environment variable `{name}` is invalid: {e}"));
+            }
+        }
+        for (i, idle) in self.idle.actions.iter().enumerate() {
+            match (idle.action, &idle.command) {
+                (None, None) => problems.push(format!(
+                    "idle action {i} has neither an `action` nor a `command`"
+                )),
+                (Some(_), Some(_)) => problems.push(format!(
+                    "idle action {i} has both an `action` and a `command`"
+                )),
+                _ => {}
+            }
+            for command in idle.command.iter().chain(&idle.resume) {
+                if let Err(e) = shell_words::split(command) {
+                    problems.push(format!("idle action {i} has an invalid command line: {e}"));
+                }
+            }
+        }
+        let history = &self.notifications.history;
+        if history.enabled && history.max_entries == 0 {
+            problems.push(
+                "notifications.history.max_entries is 0, disable the history instead".to_owned(),
+            );
+        }
+        let mut missing_deps = false;
+        for (name, service) in &self.services {
+            match service.service_type {
+                ServiceType::Systemd if service.unit.is_none() => problems.push(format!(
+                    "service `{name}` is a systemd service but has no `unit`"
+                )),
+                ServiceType::Script => match &service.script {
+                    None => problems.push(format!(
+                        "service `{name}` is a script service but has no `script`"
+                    )),
+                    Some(script) => {
+                        if let Err(e) = shell_words::split(script) {
+                            problems.push(format!("service `{name}` has an invalid `script`: {e}"));
+                        }
+                    }
+                },
+                _ => {}
+            }
+            match &service.ready {
+                Readiness::Notify if service.service_type == ServiceType::Systemd => {
+                    problems.push(format!(
+                        "service `{name}` is a systemd service, `ready = \"notify\"` only works for scripts"
+                    ))
+                }
+                Readiness::DBus(bus_name) => {
+                    if let Err(e) = zbus::names::WellKnownName::try_from(bus_name.as_str()) {
+                        problems.push(format!(
+                            "service `{name}` waits for an invalid bus name `{bus_name}`: {e}"
+                        ));
+                    }
+                }
+                _ => {}
+            }
+            for dep in service.dependencies() {
+                if !self.services.contains_key(dep) {
+                    missing_deps = true;
+                    problems.push(format!(
+                        "service `{name}` depends on `{dep}`, which is not defined"
+                    ));
+                }
+            }
+        }
+        if !missing_deps {
+            if let Err(e) = self.start_order() {
+                problems.push(e.to_string());
+            }
+        }
+        problems
+    }
+
+    /// Check that the config can be used to start a session
+    pub fn validate(&self) -> Result<()> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            bail!(problems.join("\n"));
+        }
+        Ok(())
+    }
+
+    /// Sort the services so that every service comes after its dependencies
+    pub fn start_order(&self) -> Result<Vec<&str>> {
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        enum Mark {
+            Visiting,
+            Done,
+        }
+
+        fn visit<'a>(
+            config: &'a Config,
+            name: &'a str,
+            marks: &mut BTreeMap<&'a str, Mark>,
+            stack: &mut Vec<&'a str>,
+            order: &mut Vec<&'a str>,
+        ) -> Result<()> {
+            match marks.get(name) {
+                Some(Mark::Done) => return Ok(()),
+                Some(Mark::Visiting) => {
+                    let start = stack.iter().position(|s| *s == name).unwrap_or(0);
+                    let mut cycle = stack[start..].to_vec();
+                    cycle.push(name);
+                    bail!("dependency cycle between services: {}", cycle.join(" -> "));
+                }
+                None => {}
+            }
+            let Some(service) = config.services.get(name) else {
+                bail!("service `{name}` is not defined");
+            };
+            marks.insert(name, Mark::Visiting);
+            stack.push(name);
+            for dep in service.dependencies() {
+                visit(config, dep, marks, stack, order)?;
+            }
+            stack.pop();
+            marks.insert(name, Mark::Done);
+            order.push(name);
+            Ok(())
+        }
+
+        let mut marks = BTreeMap::new();
+        let mut order = Vec::with_capacity(self.services.len());
+        for name in self.services.keys() {
+            visit(self, name, &mut marks, &mut vec![], &mut order)?;
+        }
+        Ok(order)
+    }
+}
+
+/// Directories searched for session configs, in merge order
+fn config_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![
+        PathBuf::from("/usr/share/d5/sessions"),
+        PathBuf::from("/etc/d5.conf.d"),
+    ];
+    if let Some(base) = directories::BaseDirs::new() {
+        dirs.push(base.config_dir().join("d5"));
+    }
+    dirs
+}
+
+/// Where to load the session config from
+#[derive(Debug, Clone)]
+pub enum ConfigSource {
+    /// Search the config directories for this session
+    Session(String),
+    /// Load exactly this file
+    File(PathBuf),
+}
+
+impl ConfigSource {
+    /// Every path this source may load, in merge order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        match self {
+            ConfigSource::Session(name) => config_dirs()
+                .into_iter()
+                .map(|dir| dir.join(format!("{name}.toml")))
+                .collect(),
+            ConfigSource::File(path) => vec![path.clone()],
+        }
+    }
+}
+
+/// Merge `overlay` into `base`, recursing into tables and replacing everything else
+pub fn merge(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn read_toml(path: &Path) -> Result<Option<toml::Value>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(
+            toml::from_str(&contents).map_err(|e| eyre!("{}: {}", path.display(), e))?,
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(eyre!("{}: {}", path.display(), e)),
+    }
+}
+
+/// Load and merge every config file for the source, without interpreting it
+pub fn load_merged(source: &ConfigSource) -> Result<toml::Value> {
+    let paths = source.paths();
+    let mut merged: Option<toml::Value> = None;
+    for path in &paths {
+        let value = read_toml(path).map_err(|e| eyre!("{e}, tried:\n{}", tried(&paths)))?;
+        let Some(value) = value else {
+            continue;
+        };
+        tracing::debug!("Loading config from {}", path.display());
+        match &mut merged {
+            Some(merged) => merge(merged, value),
+            None => merged = Some(value),
+        }
+    }
+    merged.ok_or_else(|| eyre!("no session config found, tried:\n{}", tried(&paths)))
+}
+
+/// The config paths for an error message, one per line
+fn tried(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| format!("  {}", p.display()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Interpret a merged config, returning the keys d5 does not know about
+pub fn parse(value: toml::Value) -> Result<(Config, Vec<String>)> {
+    let mut unknown = vec![];
+    let config = serde_ignored::deserialize(value, |path| unknown.push(path.to_string()))?;
+    Ok((config, unknown))
 }
 
 // load config
-pub fn load_config(name: &str) -> Result<Config> {
-    let config = std::fs::read_to_string(format!("/etc/d5.conf.d/{}.toml", name))?;
-    let config: Config = toml::from_str(&config)?;
+pub fn load_config(source: &ConfigSource) -> Result<Config> {
+    let (config, unknown) = parse(load_merged(source)?)
+        .map_err(|e| eyre!("{e}, tried:\n{}", tried(&source.paths())))?;
+    for key in unknown {
+        tracing::warn!("Ignoring unknown config key `{}`", key);
+    }
+    config.validate()?;
     Ok(config)
 }
+
+#[test]
+fn example_config_parses() {
+    let config: Config = toml::from_str(include_str!("../../d5.example.toml")).unwrap();
+    config.validate().unwrap();
+
+    let ibus = &config.services["ibus"];
+    assert_eq!(ibus.service_type, ServiceType::Systemd);
+    assert_eq!(ibus.unit.as_deref(), Some("ibus.service"));
+
+    let mondai = &config.services["mondai"];
+    assert_eq!(mondai.service_type, ServiceType::Script);
+    assert_eq!(mondai.script.as_deref(), Some("mondai"));
+}
+
+#[test]
+fn dependency_cycles_are_rejected() {
+    let config: Config = toml::from_str(
+        r#"
+        [session]
+        leader = "kiri"
+
+        [services.a]
+        type = "script"
+        script = "a"
+        after = ["b"]
+
+        [services.b]
+        type = "script"
+        script = "b"
+        requires = ["a"]
+        "#,
+    )
+    .unwrap();
+    let err = config.validate().unwrap_err().to_string();
+    assert_eq!(err, "dependency cycle between services: a -> b -> a");
+}
+
+#[test]
+fn overrides_are_deep_merged() {
+    let mut base: toml::Value = toml::from_str(include_str!("../../d5.example.toml")).unwrap();
+    let overlay: toml::Value = toml::from_str(
+        r#"
+        [session]
+        leader = "kiri"
+
+        [services.mondai]
+        restart = "always"
+        "#,
+    )
+    .unwrap();
+    merge(&mut base, overlay);
+    let config: Config = base.try_into().unwrap();
+
+    assert_eq!(config.session.leader, "kiri");
+    assert!(config.session.autostart);
+    let mondai = &config.services["mondai"];
+    assert_eq!(mondai.restart, RestartPolicy::Always);
+    assert_eq!(mondai.script.as_deref(), Some("mondai"));
+    assert!(config.services.contains_key("ibus"));
+}
+
+#[test]
+fn idle_actions_need_one_action() {
+    let config: Config = toml::from_str(
+        r#"
+        [session]
+        leader = "kiri"
+
+        [[idle.actions]]
+        after = 60
+
+        [[idle.actions]]
+        after = 120
+        action = "lock"
+        command = "swaylock"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.problems(),
+        [
+            "idle action 0 has neither an `action` nor a `command`",
+            "idle action 1 has both an `action` and a `command`",
+        ]
+    );
+}
+
+#[test]
+fn environment_table_parses() {
+    let config: Config = toml::from_str(
+        r#"
+        [session]
+        leader = "kiri"
+
+        [environment]
+        QT_QPA_PLATFORM = "wayland;xcb"
+        PATH = "${HOME}/.local/bin:${PATH}"
+        unset = ["GDK_BACKEND"]
+        "#,
+    )
+    .unwrap();
+    assert!(config.problems().is_empty());
+    assert_eq!(config.environment.unset, ["GDK_BACKEND"]);
+    assert_eq!(config.environment.vars["QT_QPA_PLATFORM"], "wayland;xcb");
+    let exported = config.export_env();
+    assert!(exported.contains(&"GDK_BACKEND".to_owned()));
+    assert!(exported.contains(&"PATH".to_owned()));
+}
+
+#[test]
+fn readiness_parses() {
+    let config: Config = toml::from_str(
+        r#"
+        [session]
+        leader = "kiri"
+
+        [services.portal]
+        type = "script"
+        script = "xdg-desktop-portal-kiri"
+        ready = "dbus:org.freedesktop.impl.portal.desktop.kiri"
+
+        [services.panel]
+        type = "script"
+        script = "kiri-panel"
+        ready = "notify"
+        ready_timeout = 90
+
+        [services.keyring]
+        type = "systemd"
+        unit = "gnome-keyring-daemon.service"
+        ready = "notify"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.services["portal"].ready,
+        Readiness::DBus("org.freedesktop.impl.portal.desktop.kiri".to_owned())
+    );
+    assert_eq!(config.services["panel"].ready, Readiness::Notify);
+    assert_eq!(config.services["panel"].ready_timeout, 90);
+    assert_eq!(config.services["portal"].ready_timeout, 30);
+    assert_eq!(
+        config.problems(),
+        ["service `keyring` is a systemd service, `ready = \"notify\"` only works for scripts"]
+    );
+
+    let err = toml::from_str::<ServiceConfig>("type = \"script\"\nready = \"socket\"")
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("unknown readiness `socket`"));
+}
+
+#[test]
+fn load_errors_list_the_paths_tried() {
+    let path = std::env::temp_dir().join(format!("d5-broken-{}.toml", std::process::id()));
+    std::fs::write(&path, "[session\nleader = \"kiri\"\n").unwrap();
+    let err = load_merged(&ConfigSource::File(path.clone()))
+        .unwrap_err()
+        .to_string();
+    std::fs::remove_file(&path).unwrap();
+    assert!(err.starts_with(&format!("{}: ", path.display())));
+    assert!(err.ends_with(&format!(", tried:\n  {}", path.display())));
+}
+
+#[test]
+fn unknown_keys_are_reported() {
+    let value = toml::from_str(
+        r#"
+        [session]
+        leader = "kiri"
+        autostrat = true
+
+        [services.mondai]
+        type = "script"
+        script = "mondai"
+        restrat = "always"
+        "#,
+    )
+    .unwrap();
+    let (config, unknown) = parse(value).unwrap();
+    assert!(!config.session.autostart);
+    assert_eq!(unknown, ["services.mondai.restrat", "session.autostrat"]);
+}
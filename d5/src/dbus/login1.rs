@@ -0,0 +1,22 @@
+//! # DBus interface proxy for the inhibitor locks of: `org.freedesktop.login1.Manager`
+//!
+//! Only what `logind_zbus` does not cover. See `org.freedesktop.login1(5)`.
+
+use zbus::dbus_proxy;
+
+/// What, who, why, mode, user and process of an inhibitor lock
+pub type InhibitorLock = (String, String, String, String, u32, u32);
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait Inhibitors {
+    /// ListInhibitors method
+    fn list_inhibitors(&self) -> zbus::Result<Vec<InhibitorLock>>;
+
+    /// BlockInhibited property, what is blocked separated by colons
+    #[dbus_proxy(property)]
+    fn block_inhibited(&self) -> zbus::Result<String>;
+}
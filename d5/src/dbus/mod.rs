@@ -1 +1,2 @@
-mod notifier;
\ No newline at end of file
+pub mod login1;
+pub mod notifier;
@@ -37,6 +37,7 @@ trait Notifications {
     fn get_server_information(&self) -> zbus::Result<(String, String, String, String)>;
 
     /// Notify method
+    #[allow(clippy::too_many_arguments)]
     fn notify(
         &self,
         arg_0: &str,
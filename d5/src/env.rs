@@ -1,37 +1,335 @@
 //! Environment module for d5
 //! Loads environment configs from a group of files
 // It should load files from .profile and .config
+use color_eyre::eyre::{bail, eyre};
 use color_eyre::Result;
-use directories::{BaseDirs, UserDirs};
-use std::{path::PathBuf, process::Command};
+use directories::BaseDirs;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
 use tracing::{debug, log::warn};
+use zbus::fdo::DBusProxy;
+use zbus_systemd::systemd1::ManagerProxy as SystemdManagerProxy;
 
-pub fn load_envs(session: crate::cli::DisplayMode) -> Result<()> {
+use crate::cli::DisplayMode;
+use crate::config::EnvironmentConfig;
+
+/// How long the login shell gets to print its environment
+const PROFILE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Printed by the login shell right before its environment, so anything the profile prints is skipped
+const ENV_MARKER: &[u8] = b"\0D5_ENV_BEGIN\0";
+
+/// Variables that describe the shell itself rather than the session
+const SHELL_VARS: &[&str] = &["_", "SHLVL", "PWD", "OLDPWD"];
+
+/// Variables exported to activated services unless the session config says otherwise
+pub const DEFAULT_EXPORT_ENV: &[&str] = &[
+    "DISPLAY",
+    "WAYLAND_DISPLAY",
+    "XAUTHORITY",
+    "XDG_SESSION_TYPE",
+    "XDG_CURRENT_DESKTOP",
+    "XDG_SESSION_DESKTOP",
+    "DESKTOP_SESSION",
+];
+
+/// Run `shell` as a login shell and return its environment
+async fn shell_env(shell: &str) -> Result<HashMap<OsString, OsString>> {
+    let child = Command::new(shell)
+        .arg("-l")
+        .arg("-c")
+        .arg("printf '\\0D5_ENV_BEGIN\\0' && exec env -0")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let output = tokio::time::timeout(PROFILE_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| eyre!("{shell} did not finish within {PROFILE_TIMEOUT:?}"))??;
+    if !output.status.success() {
+        bail!("{shell} exited with {}", output.status);
+    }
+
+    let stdout = output.stdout;
+    let start = stdout
+        .windows(ENV_MARKER.len())
+        .position(|w| w == ENV_MARKER)
+        .ok_or_else(|| eyre!("{shell} did not print its environment"))?;
+
+    Ok(stdout[start + ENV_MARKER.len()..]
+        .split(|b| *b == 0)
+        .filter_map(|var| {
+            let eq = var.iter().position(|b| *b == b'=')?;
+            Some((
+                OsString::from_vec(var[..eq].to_vec()),
+                OsString::from_vec(var[eq + 1..].to_vec()),
+            ))
+        })
+        .collect())
+}
+
+/// Import the environment of the user's login shell into d5 and the systemd user manager
+///
+/// Only variables that the profile added or changed are applied.
+pub async fn import_login_env(systemd: &SystemdManagerProxy<'_>) -> Result<()> {
+    let user_shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_owned());
+    let mut shells = vec![user_shell.as_str()];
+    if user_shell != "/bin/sh" {
+        // a broken profile for the user's shell should not cost us the rest of the environment
+        shells.push("/bin/sh");
+    }
+
+    let mut env = None;
+    for shell in shells {
+        match shell_env(shell).await {
+            Ok(vars) => {
+                env = Some(vars);
+                break;
+            }
+            Err(e) => warn!(
+                "Failed to load the login environment from {}: {:?}",
+                shell, e
+            ),
+        }
+    }
+    let Some(env) = env else {
+        warn!("Continuing without the login environment");
+        return Ok(());
+    };
+
+    let mut changed = env
+        .into_iter()
+        .filter(|(key, _)| !SHELL_VARS.iter().any(|v| key == v))
+        .filter(|(key, value)| std::env::var_os(key).as_ref() != Some(value))
+        .collect::<Vec<_>>();
+    changed.sort();
+
+    let mut assignments = Vec::with_capacity(changed.len());
+    for (key, value) in changed {
+        debug!("Imported {:?} from the login shell", key);
+        std::env::set_var(&key, &value);
+        // systemd only takes UTF-8 assignments
+        match (key.to_str(), value.to_str()) {
+            (Some(key), Some(value)) => assignments.push(format!("{key}={value}")),
+            _ => warn!("Not exporting {:?} to systemd, it is not valid UTF-8", key),
+        }
+    }
+    if !assignments.is_empty() {
+        systemd.set_environment(assignments).await?;
+    }
+    Ok(())
+}
+
+/// Set the variables that follow from the display mode, before the leader is spawned
+pub fn set_display_env(display: DisplayMode) {
+    match display {
+        DisplayMode::X11 => {
+            std::env::set_var("XDG_SESSION_TYPE", "x11");
+            // left over from a Wayland session, X11 apps must not try to use it
+            std::env::remove_var("WAYLAND_DISPLAY");
+        }
+        DisplayMode::Wayland => std::env::set_var("XDG_SESSION_TYPE", "wayland"),
+    }
+}
+
+/// Apply the session's `[environment]` table, before the leader and services are spawned
+///
+/// Values are expanded against the environment as it was before the table is applied.
+pub fn set_session_env(config: &EnvironmentConfig) -> Result<()> {
+    let vars = config
+        .vars
+        .iter()
+        .map(|(name, value)| {
+            let value = expand(value, |var| std::env::var(var).ok())
+                .map_err(|e| eyre!("environment variable `{name}`: {e}"))?;
+            Ok((name, value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for name in &config.unset {
+        debug!("Unsetting {}", name);
+        std::env::remove_var(name);
+    }
+    for (name, value) in vars {
+        debug!("Setting {}={}", name, value);
+        std::env::set_var(name, value);
+    }
+    Ok(())
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` in `value`, looking variables up with `lookup`
+///
+/// Like in the shell, the default is used when the variable is unset or empty, and may itself
+/// contain expansions. A `$` that does not start `${` is kept as is.
+pub fn expand(value: &str, lookup: impl Fn(&str) -> Option<String> + Copy) -> Result<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let inner = &rest[start + 2..];
+
+        // find the matching brace, defaults can nest expansions
+        let mut depth = 0;
+        let mut end = None;
+        for (i, c) in inner.char_indices() {
+            match c {
+                '{' if inner[..i].ends_with('$') => depth += 1,
+                '}' if depth == 0 => {
+                    end = Some(i);
+                    break;
+                }
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+        let end = end.ok_or_else(|| eyre!("`${{` is never closed in `{value}`"))?;
+        let expansion = &inner[..end];
+        rest = &inner[end + 1..];
+
+        let (name, default) = match expansion.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expansion, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("`{name}` is not a variable name in `{value}`");
+        }
+        match (
+            lookup(name).filter(|v| !v.is_empty() || default.is_none()),
+            default,
+        ) {
+            (Some(v), _) => out.push_str(&v),
+            (None, Some(default)) => out.push_str(&expand(default, lookup)?),
+            (None, None) => {}
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Push session variables into the systemd user manager and the D-Bus activation environment
+///
+/// Variables that are not set in d5 are unset in systemd, so nothing is left over from a previous session.
+pub async fn export_session_env(
+    conn: &zbus::Connection,
+    systemd: &SystemdManagerProxy<'_>,
+    names: &[String],
+) -> Result<()> {
+    let (set, unset): (Vec<_>, Vec<_>) = names
+        .iter()
+        .map(|name| (name.as_str(), std::env::var(name).ok()))
+        .partition(|(_, value)| value.is_some());
+    let set = set
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect::<Vec<_>>();
+    let unset = unset
+        .into_iter()
+        .map(|(name, _)| name.to_owned())
+        .collect::<Vec<_>>();
+    debug!("Exporting {:?}, unsetting {:?}", set, unset);
+
+    systemd
+        .set_environment(set.iter().map(|(k, v)| format!("{k}={v}")).collect())
+        .await?;
+    if !unset.is_empty() {
+        systemd.unset_environment(unset).await?;
+    }
+    DBusProxy::new(conn)
+        .await?
+        .update_activation_environment(set.iter().map(|(k, v)| (*k, v.as_str())).collect())
+        .await?;
+    Ok(())
+}
+
+/// The values the systemd user manager had for the session's variables before the session
+///
+/// The user manager outlives the session, so logging out puts these back instead of unsetting
+/// everything the session exported.
+pub struct PreviousEnv(Vec<(String, Option<String>)>);
+
+impl PreviousEnv {
+    /// Remember systemd's values for `names`, before they are exported
+    pub async fn save(systemd: &SystemdManagerProxy<'_>, names: &[String]) -> Result<Self> {
+        Ok(Self(previous_values(&systemd.environment().await?, names)))
+    }
+}
+
+/// The value of each of `names` in `env`, a list of `NAME=value` assignments
+fn previous_values(env: &[String], names: &[String]) -> Vec<(String, Option<String>)> {
+    names
+        .iter()
+        .map(|name| {
+            let value = env.iter().find_map(|assignment| {
+                let (n, value) = assignment.split_once('=')?;
+                (n == name).then(|| value.to_owned())
+            });
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+/// Undo the session's exports in the systemd user manager and the D-Bus activation environment
+///
+/// Variables the session added are unset, the others get their previous values back.
+pub async fn unexport_session_env(
+    conn: &zbus::Connection,
+    systemd: &SystemdManagerProxy<'_>,
+    previous: &PreviousEnv,
+) -> Result<()> {
+    let (restore, added): (Vec<_>, Vec<_>) = previous.0.iter().partition(|(_, v)| v.is_some());
+    let restore = restore
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.as_deref()?)))
+        .collect::<Vec<_>>();
+    let added = added
+        .into_iter()
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    debug!("Restoring {:?}, unsetting {:?}", restore, added);
+
+    // the activation environment cannot unset variables and would keep an empty value as set, so
+    // it only gets the values back; it may pass them on to systemd, so it goes first
+    let activation = restore
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .copied()
+        .collect::<HashMap<_, _>>();
+    if !activation.is_empty() {
+        DBusProxy::new(conn)
+            .await?
+            .update_activation_environment(activation)
+            .await?;
+    }
+    if !restore.is_empty() {
+        systemd
+            .set_environment(restore.iter().map(|(k, v)| format!("{k}={v}")).collect())
+            .await?;
+    }
+    if !added.is_empty() {
+        systemd.unset_environment(added).await?;
+    }
+    Ok(())
+}
+
+pub async fn load_envs(session: DisplayMode) -> Result<()> {
     let b = BaseDirs::new();
-    let u = UserDirs::new();
 
     // set tracing target
 
     let mut envs: Vec<PathBuf> = vec![];
 
-    if let Some(files) = b {
-        // push as Path
-        // source .profile specially because it's a bash script not a full env file
-        tracing::span!(tracing::Level::DEBUG, "Loading .profile").in_scope({
-            || {
-                let profile_path = files.home_dir().join(".profile");
-                if profile_path.exists() {
-                    Command::new("sh")
-                        .arg("-c")
-                        .arg(format!("source {}", profile_path.to_str().unwrap()))
-                        .spawn()
-                        .unwrap()
-                        .wait()
-                        .unwrap();
-                }
-            }
-        });
+    // source the login shell specially because .profile is a shell script, not a full env file
+    let conn = zbus::Connection::session().await?;
+    let systemd = SystemdManagerProxy::new(&conn).await?;
+    import_login_env(&systemd).await?;
 
+    if let Some(files) = b {
         // load from .config/environment.d
         // turns out systemd already takes care of this
 
@@ -61,3 +359,54 @@ pub fn load_envs(session: crate::cli::DisplayMode) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+fn lookup(name: &str) -> Option<String> {
+    match name {
+        "HOME" => Some("/home/kiri".to_owned()),
+        "EMPTY" => Some(String::new()),
+        _ => None,
+    }
+}
+
+#[test]
+fn variables_are_expanded() {
+    assert_eq!(
+        expand("${HOME}/.local/bin:$PATH", lookup).unwrap(),
+        "/home/kiri/.local/bin:$PATH"
+    );
+    assert_eq!(expand("${MISSING}", lookup).unwrap(), "");
+    assert_eq!(expand("${MISSING:-wayland}", lookup).unwrap(), "wayland");
+    assert_eq!(expand("${EMPTY:-x11}", lookup).unwrap(), "x11");
+    assert_eq!(expand("${EMPTY}", lookup).unwrap(), "");
+    assert_eq!(
+        expand("${MISSING:-${HOME}/cache}", lookup).unwrap(),
+        "/home/kiri/cache"
+    );
+}
+
+#[test]
+fn bad_expansions_are_rejected() {
+    assert!(expand("${HOME", lookup).is_err());
+    assert!(expand("${}", lookup).is_err());
+    assert!(expand("${HOME-x}", lookup).is_err());
+}
+
+#[test]
+fn previous_values_are_found() {
+    let env = [
+        "PATH=/usr/bin".to_owned(),
+        "EMPTY=".to_owned(),
+        "A=b=c".to_owned(),
+    ];
+    let names = ["PATH", "EMPTY", "A", "WAYLAND_DISPLAY"].map(str::to_owned);
+    assert_eq!(
+        previous_values(&env, &names),
+        [
+            ("PATH".to_owned(), Some("/usr/bin".to_owned())),
+            ("EMPTY".to_owned(), Some(String::new())),
+            ("A".to_owned(), Some("b=c".to_owned())),
+            ("WAYLAND_DISPLAY".to_owned(), None),
+        ]
+    );
+}
@@ -0,0 +1,766 @@
+//! `org.gnome.SessionManager` compatibility
+//!
+//! GTK, GNOME apps and gnome-settings-daemon expect a GNOME session manager on the bus,
+//! to register as clients, take inhibitors and ask for a logout. d5 serves a compatible
+//! object next to `com.fyralabs.d5`.
+
+use event_listener::Event;
+use futures::StreamExt;
+use logind_zbus::manager::ManagerProxy;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use zbus::fdo::{self, DBusProxy};
+use zbus::names::{BusName, UniqueName};
+use zbus::zvariant::{ObjectPath, OwnedObjectPath};
+use zbus::{dbus_interface, Connection, MessageHeader, ObjectServer, SignalContext};
+
+use crate::inhibit::InhibitorRegistry;
+use crate::session::{can_end, request_end, EndAction, SessionEnd};
+use crate::util::message_sender;
+
+pub const GNOME_SM_NAME: &str = "org.gnome.SessionManager";
+pub const GNOME_SM_PATH: &str = "/org/gnome/SessionManager";
+
+/// Answer of a client to QueryEndSession or EndSession
+#[derive(Default)]
+struct Response {
+    answer: Mutex<Option<(bool, String)>>,
+    answered: Event,
+}
+
+/// `org.gnome.SessionManager.Client`, the public side of a registered client
+struct Client {
+    app_id: String,
+    startup_id: String,
+    pid: u32,
+}
+
+#[dbus_interface(name = "org.gnome.SessionManager.Client")]
+impl Client {
+    fn get_app_id(&self) -> String {
+        self.app_id.clone()
+    }
+
+    fn get_startup_id(&self) -> String {
+        self.startup_id.clone()
+    }
+
+    /// `GSM_CLIENT_RESTART_NEVER`, d5 does not restart clients
+    fn get_restart_style_hint(&self) -> u32 {
+        0
+    }
+
+    fn get_unix_process_id(&self) -> u32 {
+        self.pid
+    }
+
+    /// `GSM_CLIENT_REGISTERED`
+    fn get_status(&self) -> u32 {
+        1
+    }
+
+    async fn stop(&self, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<()> {
+        ClientPrivate::stop(&ctxt).await?;
+        Ok(())
+    }
+}
+
+/// `org.gnome.SessionManager.ClientPrivate`, how d5 talks to a registered client
+struct ClientPrivate {
+    /// Unique name of the client's connection
+    sender: String,
+    response: Arc<Response>,
+}
+
+#[dbus_interface(name = "org.gnome.SessionManager.ClientPrivate")]
+impl ClientPrivate {
+    fn end_session_response(
+        &self,
+        is_ok: bool,
+        reason: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> fdo::Result<()> {
+        // only the client itself may answer for it
+        if message_sender(&header)? != self.sender {
+            return Err(fdo::Error::AccessDenied(
+                "only the client may answer for itself".to_owned(),
+            ));
+        }
+        *self.response.answer.lock() = Some((is_ok, reason));
+        self.response.answered.notify(usize::MAX);
+        Ok(())
+    }
+
+    #[dbus_interface(signal)]
+    async fn stop(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn query_end_session(ctxt: &SignalContext<'_>, flags: u32) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn end_session(ctxt: &SignalContext<'_>, flags: u32) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn cancel_end_session(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+}
+
+struct ClientInfo {
+    app_id: String,
+    sender: String,
+    response: Arc<Response>,
+}
+
+/// `org.gnome.SessionManager`
+pub struct SessionManager {
+    end: SessionEnd,
+    logind: ManagerProxy<'static>,
+    /// Registered clients by object path
+    ///
+    /// Methods that add or remove client objects take `&self`. zbus answers `GetAll` with the
+    /// object tree locked, so holding this interface mutably while adding objects can deadlock.
+    clients: Mutex<BTreeMap<String, ClientInfo>>,
+    next_id: AtomicU32,
+}
+
+impl SessionManager {
+    pub fn new(end: SessionEnd, logind: ManagerProxy<'static>) -> Self {
+        Self {
+            end,
+            logind,
+            clients: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU32::new(1),
+        }
+    }
+
+    async fn remove_client(
+        &self,
+        server: &ObjectServer,
+        ctxt: &SignalContext<'_>,
+        path: &ObjectPath<'_>,
+    ) -> fdo::Result<bool> {
+        if self.clients.lock().remove(path.as_str()).is_none() {
+            return Ok(false);
+        }
+        server.remove::<Client, _>(path).await?;
+        server.remove::<ClientPrivate, _>(path).await?;
+        Self::client_removed(ctxt, path.clone()).await?;
+        Ok(true)
+    }
+
+    /// Forget every client owned by a bus name that left the bus
+    async fn remove_sender(
+        &self,
+        server: &ObjectServer,
+        ctxt: &SignalContext<'_>,
+        sender: &str,
+    ) -> fdo::Result<()> {
+        let paths = self
+            .clients
+            .lock()
+            .iter()
+            .filter(|(_, c)| c.sender == sender)
+            .map(|(p, _)| p.clone())
+            .collect::<Vec<_>>();
+        for path in paths {
+            debug!("Client {} left the bus", path);
+            let path = ObjectPath::try_from(path.as_str()).map_err(zbus::Error::from)?;
+            self.remove_client(server, ctxt, &path).await?;
+        }
+        Ok(())
+    }
+}
+
+#[dbus_interface(name = "org.gnome.SessionManager")]
+impl SessionManager {
+    async fn register_client(
+        &self,
+        app_id: String,
+        client_startup_id: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<OwnedObjectPath> {
+        let sender = message_sender(&header)?;
+        let pid = match UniqueName::try_from(sender.as_str()) {
+            Ok(name) => DBusProxy::new(conn)
+                .await?
+                .get_connection_unix_process_id(BusName::from(name))
+                .await
+                .unwrap_or(0),
+            Err(_) => 0,
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let path = OwnedObjectPath::try_from(format!("{GNOME_SM_PATH}/Client{id}"))
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        let response = Arc::new(Response::default());
+        server
+            .at(
+                &path,
+                Client {
+                    app_id: app_id.clone(),
+                    startup_id: client_startup_id,
+                    pid,
+                },
+            )
+            .await?;
+        server
+            .at(
+                &path,
+                ClientPrivate {
+                    sender: sender.clone(),
+                    response: response.clone(),
+                },
+            )
+            .await?;
+        info!("Registered client {} as {}", app_id, path.as_str());
+        self.clients.lock().insert(
+            path.to_string(),
+            ClientInfo {
+                app_id,
+                sender,
+                response,
+            },
+        );
+        Self::client_added(&ctxt, path.as_ref()).await?;
+        Ok(path)
+    }
+
+    async fn unregister_client(
+        &self,
+        client_id: ObjectPath<'_>,
+        #[zbus(object_server)] server: &ObjectServer,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        if !self.remove_client(server, &ctxt, &client_id).await? {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "{} is not a registered client",
+                client_id.as_str()
+            )));
+        }
+        Ok(())
+    }
+
+    fn get_clients(&self) -> Vec<OwnedObjectPath> {
+        self.clients
+            .lock()
+            .keys()
+            .filter_map(|path| OwnedObjectPath::try_from(path.as_str()).ok())
+            .collect()
+    }
+
+    async fn inhibit(
+        &self,
+        app_id: String,
+        _toplevel_xid: u32,
+        reason: String,
+        flags: u32,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] conn: &Connection,
+    ) -> fdo::Result<u32> {
+        let sender = message_sender(&header)?;
+        crate::inhibit::inhibit(conn, &app_id, &reason, flags, &sender).await
+    }
+
+    fn uninhibit(
+        &self,
+        inhibit_cookie: u32,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> fdo::Result<()> {
+        crate::inhibit::uninhibit(inhibit_cookie, &message_sender(&header)?)
+    }
+
+    fn is_inhibited(&self, flags: u32) -> bool {
+        InhibitorRegistry::fetch().is_inhibited(flags)
+    }
+
+    /// `mode` is 0 for a normal logout, 1 without confirmation and 2 to force it
+    fn logout(&self, mode: u32) {
+        info!(
+            "Logout requested through org.gnome.SessionManager (mode {})",
+            mode
+        );
+        self.end.request(EndAction::Logout, mode == 2);
+    }
+
+    async fn shutdown(&self) -> fdo::Result<()> {
+        info!("Shutdown requested through org.gnome.SessionManager");
+        request_end(&self.end, &self.logind, EndAction::PowerOff).await
+    }
+
+    async fn reboot(&self) -> fdo::Result<()> {
+        info!("Reboot requested through org.gnome.SessionManager");
+        request_end(&self.end, &self.logind, EndAction::Reboot).await
+    }
+
+    async fn can_shutdown(&self) -> fdo::Result<bool> {
+        Ok(can_end(&self.logind, EndAction::PowerOff).await?)
+    }
+
+    fn is_session_running(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn session_name(&self) -> String {
+        std::env::var("XDG_SESSION_DESKTOP").unwrap_or_else(|_| "d5".to_owned())
+    }
+
+    #[dbus_interface(property)]
+    fn session_is_active(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn inhibited_actions(&self) -> u32 {
+        InhibitorRegistry::fetch().flags()
+    }
+
+    #[dbus_interface(signal)]
+    async fn client_added(ctxt: &SignalContext<'_>, id: ObjectPath<'_>) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn client_removed(ctxt: &SignalContext<'_>, id: ObjectPath<'_>) -> zbus::Result<()>;
+}
+
+/// Forget clients of bus names that disconnect
+pub fn watch_disconnects(conn: Connection) {
+    tokio::spawn(async move {
+        let result: zbus::Result<()> = async {
+            let dbus = DBusProxy::new(&conn).await?;
+            let mut changes = dbus.receive_name_owner_changed().await?;
+            let server = conn.object_server();
+            let iface = server.interface::<_, SessionManager>(GNOME_SM_PATH).await?;
+            while let Some(change) = changes.next().await {
+                let args = change.args()?;
+                // unique names lose their owner when the connection goes away
+                if args.new_owner().is_some() || !args.name().starts_with(':') {
+                    continue;
+                }
+                let manager = iface.get().await;
+                if let Err(e) = manager
+                    .remove_sender(&server, iface.signal_context(), args.name())
+                    .await
+                {
+                    warn!("Failed to clean up after {}: {:?}", args.name(), e);
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Stopped watching for disconnected clients: {:?}", e);
+        }
+    });
+}
+
+/// Keep `InhibitedActions` up to date, inhibitors can also be taken through d5's own interface
+pub fn watch_inhibitors(conn: Connection) {
+    tokio::spawn(async move {
+        let result: zbus::Result<()> = async {
+            let iface = conn
+                .object_server()
+                .interface::<_, SessionManager>(GNOME_SM_PATH)
+                .await?;
+            loop {
+                let changed = InhibitorRegistry::fetch().changed.listen();
+                changed.await;
+                iface
+                    .get()
+                    .await
+                    .inhibited_actions_changed(iface.signal_context())
+                    .await?;
+            }
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Stopped announcing inhibitor changes: {:?}", e);
+        }
+    });
+}
+
+/// Send a signal to every client and wait until they all answer, or the timeout passes
+///
+/// Returns the clients that refused, with their reasons.
+async fn ask_clients(
+    conn: &Connection,
+    signal: impl Fn(SignalContext<'static>) -> futures::future::BoxFuture<'static, zbus::Result<()>>,
+    timeout: Duration,
+) -> zbus::Result<Vec<(String, String)>> {
+    let iface = conn
+        .object_server()
+        .interface::<_, SessionManager>(GNOME_SM_PATH)
+        .await?;
+    let clients = iface
+        .get()
+        .await
+        .clients
+        .lock()
+        .iter()
+        .map(|(path, c)| (path.clone(), c.app_id.clone(), c.response.clone()))
+        .collect::<Vec<_>>();
+
+    let mut waiting = Vec::with_capacity(clients.len());
+    for (path, app_id, response) in clients {
+        *response.answer.lock() = None;
+        let answered = response.answered.listen();
+        signal(SignalContext::new(conn, path)?.into_owned()).await?;
+        waiting.push(async move {
+            if response.answer.lock().is_none()
+                && tokio::time::timeout(timeout, answered).await.is_err()
+            {
+                debug!("Client {} did not answer in time", app_id);
+            }
+            let answer = response.answer.lock().clone();
+            match answer {
+                Some((false, reason)) => Some((app_id, reason)),
+                _ => None,
+            }
+        });
+    }
+    Ok(futures::future::join_all(waiting)
+        .await
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+/// Ask every client whether the session may end
+pub async fn query_end_session(
+    conn: &Connection,
+    flags: u32,
+    timeout: Duration,
+) -> zbus::Result<Vec<(String, String)>> {
+    ask_clients(
+        conn,
+        move |ctxt| Box::pin(async move { ClientPrivate::query_end_session(&ctxt, flags).await }),
+        timeout,
+    )
+    .await
+}
+
+/// Tell every client the session is ending, and wait for them to get ready
+pub async fn end_session(conn: &Connection, flags: u32, timeout: Duration) -> zbus::Result<()> {
+    ask_clients(
+        conn,
+        move |ctxt| Box::pin(async move { ClientPrivate::end_session(&ctxt, flags).await }),
+        timeout,
+    )
+    .await?;
+    Ok(())
+}
+
+async fn client_paths(conn: &Connection) -> zbus::Result<Vec<String>> {
+    let iface = conn
+        .object_server()
+        .interface::<_, SessionManager>(GNOME_SM_PATH)
+        .await?;
+    let paths = iface.get().await.clients.lock().keys().cloned().collect();
+    Ok(paths)
+}
+
+/// Tell every client that the session goes on after all
+pub async fn cancel_end_session(conn: &Connection) -> zbus::Result<()> {
+    for path in client_paths(conn).await? {
+        ClientPrivate::cancel_end_session(&SignalContext::new(conn, path)?).await?;
+    }
+    Ok(())
+}
+
+/// Tell every client to exit
+pub async fn stop_clients(conn: &Connection) -> zbus::Result<()> {
+    for path in client_paths(conn).await? {
+        ClientPrivate::stop(&SignalContext::new(conn, path)?).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+use crate::inhibit::{INHIBIT_IDLE, INHIBIT_LOGOUT, INHIBIT_SUSPEND, INHIBIT_SWITCH_USER};
+#[cfg(test)]
+use crate::testbus::PrivateBus;
+#[cfg(test)]
+use zbus::{dbus_proxy, ConnectionBuilder};
+
+#[cfg(test)]
+#[dbus_proxy(
+    interface = "org.gnome.SessionManager",
+    default_service = "org.gnome.SessionManager",
+    default_path = "/org/gnome/SessionManager"
+)]
+trait GnomeSessionManager {
+    fn register_client(&self, app_id: &str, startup_id: &str) -> zbus::Result<OwnedObjectPath>;
+    fn unregister_client(&self, client_id: &ObjectPath<'_>) -> zbus::Result<()>;
+    fn get_clients(&self) -> zbus::Result<Vec<OwnedObjectPath>>;
+    fn inhibit(&self, app_id: &str, xid: u32, reason: &str, flags: u32) -> zbus::Result<u32>;
+    fn uninhibit(&self, cookie: u32) -> zbus::Result<()>;
+    fn is_inhibited(&self, flags: u32) -> zbus::Result<bool>;
+    fn logout(&self, mode: u32) -> zbus::Result<()>;
+    fn shutdown(&self) -> zbus::Result<()>;
+    fn reboot(&self) -> zbus::Result<()>;
+    fn can_shutdown(&self) -> zbus::Result<bool>;
+    #[dbus_proxy(property)]
+    fn inhibited_actions(&self) -> zbus::Result<u32>;
+}
+
+#[cfg(test)]
+#[dbus_proxy(
+    interface = "org.gnome.SessionManager.Client",
+    default_service = "org.gnome.SessionManager"
+)]
+trait GnomeClient {
+    fn get_app_id(&self) -> zbus::Result<String>;
+}
+
+#[cfg(test)]
+#[dbus_proxy(
+    interface = "org.gnome.SessionManager.ClientPrivate",
+    default_service = "org.gnome.SessionManager"
+)]
+trait GnomeClientPrivate {
+    fn end_session_response(&self, is_ok: bool, reason: &str) -> zbus::Result<()>;
+    #[dbus_proxy(signal)]
+    fn query_end_session(&self, flags: u32) -> zbus::Result<()>;
+}
+
+/// Serve org.gnome.SessionManager on `bus` the way the session does
+#[cfg(test)]
+async fn serve(bus: &PrivateBus, end: SessionEnd) -> Connection {
+    // the proxy keeps the fake logind on the bus
+    let logind = ManagerProxy::new(&crate::testbus::serve_logind(bus).await)
+        .await
+        .unwrap();
+    let conn = ConnectionBuilder::address(bus.address())
+        .unwrap()
+        .name(GNOME_SM_NAME)
+        .unwrap()
+        .serve_at(GNOME_SM_PATH, SessionManager::new(end, logind))
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+    watch_disconnects(conn.clone());
+    watch_inhibitors(conn.clone());
+    crate::inhibit::watch_disconnects(conn.clone());
+    conn
+}
+
+#[tokio::test]
+async fn clients_register_and_unregister() {
+    let bus = private_bus!();
+    let _server = serve(&bus, SessionEnd::new()).await;
+    let conn = bus.connect().await;
+    let manager = GnomeSessionManagerProxy::new(&conn).await.unwrap();
+
+    let path = manager
+        .register_client("org.example.App", "")
+        .await
+        .unwrap();
+    let client = GnomeClientProxy::builder(&conn)
+        .path(path.clone())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(client.get_app_id().await.unwrap(), "org.example.App");
+    assert_eq!(manager.get_clients().await.unwrap(), vec![path.clone()]);
+
+    manager.unregister_client(&path).await.unwrap();
+    assert!(manager.get_clients().await.unwrap().is_empty());
+    assert!(manager.unregister_client(&path).await.is_err());
+}
+
+#[tokio::test]
+async fn inhibitors_are_tracked_by_flags() {
+    let bus = private_bus!();
+    let _server = serve(&bus, SessionEnd::new()).await;
+    let conn = bus.connect().await;
+    let manager = GnomeSessionManagerProxy::new(&conn).await.unwrap();
+
+    let cookie = manager
+        .inhibit(
+            "org.example.Player",
+            0,
+            "Playing video",
+            INHIBIT_IDLE | INHIBIT_SUSPEND,
+        )
+        .await
+        .unwrap();
+    assert!(manager.is_inhibited(INHIBIT_IDLE).await.unwrap());
+    assert!(!manager
+        .is_inhibited(INHIBIT_LOGOUT | INHIBIT_SWITCH_USER)
+        .await
+        .unwrap());
+    assert_eq!(
+        manager.inhibited_actions().await.unwrap(),
+        INHIBIT_IDLE | INHIBIT_SUSPEND
+    );
+
+    manager.uninhibit(cookie).await.unwrap();
+    assert!(!manager.is_inhibited(INHIBIT_IDLE).await.unwrap());
+}
+
+#[tokio::test]
+async fn disconnecting_drops_clients_and_inhibitors() {
+    let bus = private_bus!();
+    let _server = serve(&bus, SessionEnd::new()).await;
+    let observer = GnomeSessionManagerProxy::new(&bus.connect().await)
+        .await
+        .unwrap();
+
+    let conn = bus.connect().await;
+    let manager = GnomeSessionManagerProxy::new(&conn).await.unwrap();
+    manager
+        .register_client("org.example.App", "")
+        .await
+        .unwrap();
+    manager
+        .inhibit("org.example.App", 0, "Saving", INHIBIT_LOGOUT)
+        .await
+        .unwrap();
+    drop(manager);
+    drop(conn);
+
+    for _ in 0..50 {
+        if observer.get_clients().await.unwrap().is_empty()
+            && !observer.is_inhibited(INHIBIT_LOGOUT).await.unwrap()
+        {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("client and inhibitor outlived their connection");
+}
+
+#[tokio::test]
+async fn query_end_session_collects_refusals() {
+    let bus = private_bus!();
+    let server = serve(&bus, SessionEnd::new()).await;
+
+    let conn = bus.connect().await;
+    let manager = GnomeSessionManagerProxy::new(&conn).await.unwrap();
+    let path = manager
+        .register_client("org.example.Editor", "")
+        .await
+        .unwrap();
+    let client = GnomeClientPrivateProxy::builder(&conn)
+        .path(path)
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+    let mut queries = client.receive_query_end_session().await.unwrap();
+    tokio::spawn(async move {
+        queries.next().await.unwrap();
+        client
+            .end_session_response(false, "Unsaved changes")
+            .await
+            .unwrap();
+    });
+
+    let refusals = query_end_session(&server, 0, Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(
+        refusals,
+        vec![(
+            "org.example.Editor".to_owned(),
+            "Unsaved changes".to_owned()
+        )]
+    );
+}
+
+#[tokio::test]
+async fn logout_ends_the_session() {
+    let bus = private_bus!();
+    let end = SessionEnd::new();
+    let ended = end.listen();
+    let _server = serve(&bus, end.clone()).await;
+    let conn = bus.connect().await;
+    let manager = GnomeSessionManagerProxy::new(&conn).await.unwrap();
+
+    manager.logout(1).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), ended)
+        .await
+        .unwrap();
+    assert_eq!(end.action(), EndAction::Logout);
+}
+
+#[tokio::test]
+async fn shutdown_needs_logind_to_allow_it() {
+    let bus = private_bus!();
+    let end = SessionEnd::new();
+    let mut ended = end.listen();
+    let _server = serve(&bus, end.clone()).await;
+    let conn = bus.connect().await;
+    let manager = GnomeSessionManagerProxy::new(&conn).await.unwrap();
+
+    // the fake logind wants authentication to power off, but lets the user reboot
+    assert!(!manager.can_shutdown().await.unwrap());
+    assert!(manager.shutdown().await.is_err());
+    assert!(tokio::time::timeout(Duration::from_millis(200), &mut ended)
+        .await
+        .is_err());
+
+    manager.reboot().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), ended)
+        .await
+        .unwrap();
+    assert_eq!(end.action(), EndAction::Reboot);
+}
+
+#[tokio::test]
+async fn only_the_client_answers_for_itself() {
+    let bus = private_bus!();
+    let _server = serve(&bus, SessionEnd::new()).await;
+    let conn = bus.connect().await;
+    let path = GnomeSessionManagerProxy::new(&conn)
+        .await
+        .unwrap()
+        .register_client("org.example.Editor", "")
+        .await
+        .unwrap();
+
+    let other = GnomeClientPrivateProxy::builder(&bus.connect().await)
+        .path(path)
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+    let err = other.end_session_response(true, "").await.unwrap_err();
+    assert!(matches!(
+        err,
+        zbus::Error::MethodError(name, _, _) if name == "org.freedesktop.DBus.Error.AccessDenied"
+    ));
+}
+
+#[tokio::test]
+async fn only_the_owner_uninhibits() {
+    let bus = private_bus!();
+    let _server = serve(&bus, SessionEnd::new()).await;
+    let owner = GnomeSessionManagerProxy::new(&bus.connect().await)
+        .await
+        .unwrap();
+    let other = GnomeSessionManagerProxy::new(&bus.connect().await)
+        .await
+        .unwrap();
+
+    let cookie = owner
+        .inhibit("org.example.Player", 0, "Playing video", INHIBIT_IDLE)
+        .await
+        .unwrap();
+    let err = other.uninhibit(cookie).await.unwrap_err();
+    assert!(matches!(
+        err,
+        zbus::Error::MethodError(name, _, _) if name == "org.freedesktop.DBus.Error.AccessDenied"
+    ));
+    assert!(owner.is_inhibited(INHIBIT_IDLE).await.unwrap());
+    owner.uninhibit(cookie).await.unwrap();
+    assert!(!owner.is_inhibited(INHIBIT_IDLE).await.unwrap());
+}
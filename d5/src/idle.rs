@@ -0,0 +1,266 @@
+//! Idle actions
+//!
+//! The leader tells logind when the session goes idle. d5 follows the session's `IdleHint` and
+//! works through the `[idle]` actions, from dimming the screen to suspending, then undoes what it
+//! can once the session is used again. Apps holding an idle inhibitor, taken from d5 or straight
+//! from logind, hold the actions back.
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures::StreamExt;
+use logind_zbus::manager::ManagerProxy;
+use logind_zbus::session::SessionProxy;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::process::Command;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+use zbus::PropertyStream;
+
+use crate::config::{IdleAction, IdleBuiltin, IdleConfig};
+use crate::dbus::login1::InhibitorsProxy;
+use crate::inhibit::{InhibitorRegistry, INHIBIT_IDLE};
+
+pub struct IdleActions {
+    /// Sorted by `after`
+    actions: Vec<IdleAction>,
+    /// On the session's own path, logind announces `IdleHint` changes nowhere else
+    session: SessionProxy<'static>,
+    logind: ManagerProxy<'static>,
+}
+
+impl IdleActions {
+    pub fn new(
+        config: &IdleConfig,
+        session: SessionProxy<'static>,
+        logind: ManagerProxy<'static>,
+    ) -> Self {
+        let mut actions = config.actions.clone();
+        actions.sort_by_key(|a| a.after);
+        Self {
+            actions,
+            session,
+            logind,
+        }
+    }
+
+    /// Follow the session's idle hint for as long as logind reports it
+    pub async fn watch(self) -> zbus::Result<()> {
+        let mut changes = self.session.receive_idle_hint_changed().await;
+        let inhibitors = InhibitorsProxy::new(self.logind.connection()).await?;
+        loop {
+            if !self.session.idle_hint().await? {
+                if !wait_for(&mut changes, true).await? {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            // logind knows best when the session went idle, which may be well before now
+            let start = idle_start(self.session.idle_since_hint().await?);
+            let mut taken = vec![];
+            let active = tokio::select! {
+                _ = self.climb(&inhibitors, start, &mut taken) => unreachable!("idle actions never finish"),
+                active = wait_for(&mut changes, false) => active?,
+            };
+            self.resume(&taken);
+            if !active {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Take every action in turn, recording the ones taken
+    ///
+    /// Never returns, there is nothing left to do but wait for the session to become active.
+    async fn climb(
+        &self,
+        inhibitors: &InhibitorsProxy<'_>,
+        mut start: Instant,
+        taken: &mut Vec<usize>,
+    ) {
+        // logind announces its locks coming and going through BlockInhibited
+        let mut blocked = inhibitors.receive_block_inhibited_changed().await;
+        for (i, action) in self.actions.iter().enumerate() {
+            loop {
+                let changed = InhibitorRegistry::fetch().changed.listen();
+                if InhibitorRegistry::fetch().is_inhibited(INHIBIT_IDLE)
+                    || logind_inhibits_idle(inhibitors).await
+                {
+                    debug!("Idle is inhibited");
+                    tokio::select! {
+                        _ = changed => {}
+                        _ = next_change(&mut blocked) => {}
+                    }
+                    // the session counts as idle from when the last inhibitor went away
+                    start = Instant::now();
+                    continue;
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep_until(start + Duration::from_secs(action.after)) => break,
+                    _ = changed => {}
+                    _ = next_change(&mut blocked) => {}
+                }
+            }
+            if self.take(action).await {
+                taken.push(i);
+            }
+        }
+        std::future::pending().await
+    }
+
+    /// Take an action, returning whether it was taken
+    async fn take(&self, action: &IdleAction) -> bool {
+        if action.on_battery {
+            match self.logind.on_external_power().await {
+                Ok(false) => {}
+                Ok(true) => {
+                    debug!("Not taking idle action on external power");
+                    return false;
+                }
+                Err(e) => {
+                    warn!("Failed to check for external power: {:?}", e);
+                    return false;
+                }
+            }
+        }
+
+        info!("Session idle for {}s", action.after);
+        let result = match (action.action, &action.command) {
+            (Some(IdleBuiltin::Lock), _) => self.session.lock().await.map_err(Into::into),
+            (Some(IdleBuiltin::Suspend), _) => self.logind.suspend(false).await.map_err(Into::into),
+            (None, Some(command)) => run(command),
+            (None, None) => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to take idle action: {:?}", e);
+            return false;
+        }
+        true
+    }
+
+    /// Undo the actions taken, newest first
+    fn resume(&self, taken: &[usize]) {
+        debug!("Session is no longer idle");
+        for &i in taken.iter().rev() {
+            if let Some(command) = &self.actions[i].resume {
+                if let Err(e) = run(command) {
+                    warn!("Failed to run idle resume command: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Wait until the idle hint is `idle`, returning false if logind went away first
+async fn wait_for(changes: &mut PropertyStream<'_, bool>, idle: bool) -> zbus::Result<bool> {
+    while let Some(change) = changes.next().await {
+        if change.get().await? == idle {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether anyone holds an idle block lock on logind
+async fn logind_inhibits_idle(inhibitors: &InhibitorsProxy<'_>) -> bool {
+    match inhibitors.list_inhibitors().await {
+        Ok(locks) => locks.iter().any(|(what, _, _, mode, _, _)| {
+            mode == "block" && what.split(':').any(|w| w == "idle")
+        }),
+        Err(e) => {
+            warn!("Failed to list the logind inhibitors: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Wait for a property to change, or forever if its owner went away
+async fn next_change<T: Unpin>(changes: &mut PropertyStream<'_, T>) {
+    if changes.next().await.is_none() {
+        std::future::pending().await
+    }
+}
+
+/// When the session went idle, from `IdleSinceHint` in microseconds since the epoch
+fn idle_start(since: u64) -> Instant {
+    let since = UNIX_EPOCH + Duration::from_micros(since);
+    let idle_for = SystemTime::now().duration_since(since).unwrap_or_default();
+    Instant::now()
+        .checked_sub(idle_for)
+        .unwrap_or_else(Instant::now)
+}
+
+/// Start a command without waiting for it to finish
+fn run(command: &str) -> Result<()> {
+    let argv = shell_words::split(command)?;
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| eyre!("the command is empty"))?;
+    let mut child = Command::new(program).args(args).spawn()?;
+    let command = command.to_owned();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if !status.success() => debug!("`{}` exited with {}", command, status),
+            Ok(_) => {}
+            Err(e) => debug!("Failed to wait for `{}`: {:?}", command, e),
+        }
+    });
+    Ok(())
+}
+
+#[tokio::test]
+async fn actions_count_from_idle_since_hint() {
+    use crate::testbus::{serve_logind, FakeSession, SESSION_PATH};
+    use zbus::SignalContext;
+
+    let bus = private_bus!();
+    let logind = serve_logind(&bus).await;
+    let conn = bus.connect().await;
+    let logind_manager = ManagerProxy::new(&conn).await.unwrap();
+    let session = crate::session::own_session(&conn, &logind_manager)
+        .await
+        .unwrap();
+
+    let dir = std::env::temp_dir().join(format!("d5-idle-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (dimmed, suspended) = (dir.join("dimmed"), dir.join("suspended"));
+    let config: IdleConfig = toml::from_str(&format!(
+        "[[actions]]\nafter = 60\ncommand = \"touch {}\"\n\
+         [[actions]]\nafter = 7200\ncommand = \"touch {}\"\n",
+        dimmed.display(),
+        suspended.display()
+    ))
+    .unwrap();
+    let watching = tokio::spawn(IdleActions::new(&config, session, logind_manager).watch());
+
+    // the session went idle an hour ago, logind only says so now
+    let fake = logind
+        .object_server()
+        .interface::<_, FakeSession>(SESSION_PATH)
+        .await
+        .unwrap();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    {
+        let mut fake = fake.get_mut().await;
+        fake.idle_hint = true;
+        fake.idle_since_hint = an_hour_ago.duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+    }
+    let ctxt = SignalContext::new(&logind, SESSION_PATH).unwrap();
+    fake.get()
+        .await
+        .idle_since_hint_changed(&ctxt)
+        .await
+        .unwrap();
+    fake.get().await.idle_hint_changed(&ctxt).await.unwrap();
+
+    for _ in 0..50 {
+        if dimmed.exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    watching.abort();
+    assert!(dimmed.exists());
+    assert!(!suspended.exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,221 @@
+//! Session inhibitors
+//!
+//! Apps can keep the session from logging out, switching users, suspending or going idle.
+//! Every inhibitor belongs to the unique bus name that took it, which alone may remove it, and
+//! goes away with it.
+//! Where logind has a matching lock, d5 holds one for as long as the inhibitor exists,
+//! so the system honours it too.
+
+use event_listener::Event;
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use logind_zbus::manager::{InhibitType, ManagerProxy, Mode};
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+use zbus::fdo::{self, DBusProxy};
+use zbus::names::UniqueName;
+use zbus::zvariant::OwnedFd;
+use zbus::Connection;
+
+/// Inhibitor flags, the same values as `GsmInhibitorFlag`
+pub const INHIBIT_LOGOUT: u32 = 1;
+pub const INHIBIT_SWITCH_USER: u32 = 2;
+pub const INHIBIT_SUSPEND: u32 = 4;
+pub const INHIBIT_IDLE: u32 = 8;
+
+/// logind locks backing each flag, switching users has none
+const LOGIND_LOCKS: &[(u32, InhibitType)] = &[
+    (INHIBIT_LOGOUT, InhibitType::Shutdown),
+    (INHIBIT_SUSPEND, InhibitType::Sleep),
+    (INHIBIT_IDLE, InhibitType::Idle),
+];
+
+type Registry = Arc<Mutex<InhibitorRegistry>>;
+lazy_static! {
+    static ref INHIBITOR_REGISTRY: Registry = Arc::new(Mutex::new(InhibitorRegistry::new()));
+}
+
+pub struct Inhibitor {
+    pub app_id: String,
+    pub reason: String,
+    pub flags: u32,
+    /// Unique bus name of the caller
+    pub sender: String,
+    /// Released by dropping them
+    _locks: Vec<OwnedFd>,
+}
+
+/// Inhibitors held in this session, by cookie
+pub struct InhibitorRegistry {
+    pub inhibitors: BTreeMap<u32, Inhibitor>,
+    next_cookie: u32,
+    /// Where logind locks are taken, unset if there is no logind
+    logind: Option<ManagerProxy<'static>>,
+    /// Notified whenever an inhibitor is added or removed
+    pub changed: Event,
+}
+
+impl InhibitorRegistry {
+    fn new() -> Self {
+        Self {
+            inhibitors: BTreeMap::new(),
+            next_cookie: 1,
+            logind: None,
+            changed: Event::new(),
+        }
+    }
+
+    /// Get the inhibitor registry
+    pub fn fetch() -> MutexGuard<'static, InhibitorRegistry> {
+        INHIBITOR_REGISTRY.lock()
+    }
+
+    pub fn set_logind(&mut self, logind: ManagerProxy<'static>) {
+        self.logind = Some(logind);
+    }
+
+    /// Every flag inhibited by at least one inhibitor
+    pub fn flags(&self) -> u32 {
+        self.inhibitors.values().fold(0, |flags, i| flags | i.flags)
+    }
+
+    pub fn is_inhibited(&self, flags: u32) -> bool {
+        self.flags() & flags != 0
+    }
+
+    /// Remove an inhibitor, releasing its logind locks
+    pub fn remove(&mut self, cookie: u32) -> Option<Inhibitor> {
+        let inhibitor = self.inhibitors.remove(&cookie)?;
+        debug!(
+            "{} stopped inhibiting: {}",
+            inhibitor.app_id, inhibitor.reason
+        );
+        self.changed.notify(usize::MAX);
+        Some(inhibitor)
+    }
+
+    /// Remove every inhibitor taken by a bus name
+    pub fn remove_sender(&mut self, sender: &str) {
+        let cookies = self
+            .inhibitors
+            .iter()
+            .filter(|(_, i)| i.sender == sender)
+            .map(|(cookie, _)| *cookie)
+            .collect::<Vec<_>>();
+        for cookie in cookies {
+            self.remove(cookie);
+        }
+    }
+}
+
+/// Take the logind locks matching `flags`
+async fn logind_locks(
+    logind: &ManagerProxy<'_>,
+    app_id: &str,
+    reason: &str,
+    flags: u32,
+) -> Vec<OwnedFd> {
+    let mut locks = vec![];
+    for (flag, what) in LOGIND_LOCKS {
+        if flags & flag == 0 {
+            continue;
+        }
+        match logind.inhibit(*what, app_id, reason, Mode::Block).await {
+            Ok(fd) => locks.push(fd),
+            Err(e) => warn!("Failed to take a logind lock for {}: {:?}", app_id, e),
+        }
+    }
+    locks
+}
+
+/// Add an inhibitor for `sender` and return its cookie
+pub async fn inhibit(
+    conn: &Connection,
+    app_id: &str,
+    reason: &str,
+    flags: u32,
+    sender: &str,
+) -> fdo::Result<u32> {
+    info!("{} inhibits {:#x}: {}", app_id, flags, reason);
+    // registered before taking the locks, so a sender disconnecting meanwhile still removes it
+    let (cookie, logind) = {
+        let mut registry = InhibitorRegistry::fetch();
+        let cookie = registry.next_cookie;
+        registry.next_cookie += 1;
+        registry.inhibitors.insert(
+            cookie,
+            Inhibitor {
+                app_id: app_id.to_owned(),
+                reason: reason.to_owned(),
+                flags,
+                sender: sender.to_owned(),
+                _locks: vec![],
+            },
+        );
+        registry.changed.notify(usize::MAX);
+        (cookie, registry.logind.clone())
+    };
+    // a sender that left before it was registered is never seen leaving
+    let dbus = DBusProxy::new(conn).await?;
+    if !dbus
+        .name_has_owner(
+            UniqueName::try_from(sender)
+                .map_err(zbus::Error::from)?
+                .into(),
+        )
+        .await?
+    {
+        InhibitorRegistry::fetch().remove(cookie);
+        return Err(fdo::Error::Failed(format!("{sender} left the bus")));
+    }
+
+    if let Some(logind) = logind {
+        let locks = logind_locks(&logind, app_id, reason, flags).await;
+        // locks for an inhibitor that is gone already are released right here
+        if let Some(inhibitor) = InhibitorRegistry::fetch().inhibitors.get_mut(&cookie) {
+            inhibitor._locks = locks;
+        }
+    }
+    Ok(cookie)
+}
+
+/// Remove an inhibitor on behalf of `sender`, which has to be the one that took it
+pub fn uninhibit(cookie: u32, sender: &str) -> fdo::Result<()> {
+    let mut registry = InhibitorRegistry::fetch();
+    match registry.inhibitors.get(&cookie) {
+        None => Err(fdo::Error::InvalidArgs(format!(
+            "{cookie} is not an inhibitor cookie"
+        ))),
+        Some(inhibitor) if inhibitor.sender != sender => Err(fdo::Error::AccessDenied(format!(
+            "inhibitor {cookie} belongs to another client"
+        ))),
+        Some(_) => {
+            registry.remove(cookie);
+            Ok(())
+        }
+    }
+}
+
+/// Drop the inhibitors of bus names that disconnect
+pub fn watch_disconnects(conn: Connection) {
+    tokio::spawn(async move {
+        let result: zbus::Result<()> = async {
+            let dbus = DBusProxy::new(&conn).await?;
+            let mut changes = dbus.receive_name_owner_changed().await?;
+            while let Some(change) = changes.next().await {
+                let args = change.args()?;
+                // unique names lose their owner when the connection goes away
+                if args.new_owner().is_none() && args.name().starts_with(':') {
+                    InhibitorRegistry::fetch().remove_sender(args.name());
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Stopped watching for disconnected inhibitors: {:?}", e);
+        }
+    });
+}
@@ -0,0 +1,235 @@
+//! Session leader
+//!
+//! The leader is usually the compositor. A crashed leader is restarted a limited number of times,
+//! and one that fails right after starting is replaced by the fallback leader, so the user still
+//! gets a working session instead of being thrown back to the greeter.
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+use tokio::process::{Child, Command};
+use tracing::{info, warn};
+use zbus::fdo::DBusProxy;
+use zbus::zvariant::Value;
+
+use crate::cli::DisplayMode;
+use crate::config::SessionConfig;
+use crate::dbus::notifier::NotificationsProxy;
+use crate::notify::server::NOTIFICATIONS_NAME;
+use crate::service::wait_for_name;
+use crate::wayland::WaylandSocket;
+
+/// A leader that fails within this long after starting is considered broken, not crashed
+const CRASH_WINDOW: Duration = Duration::from_secs(10);
+
+pub struct Leader {
+    pub child: Child,
+    command: String,
+    started: Instant,
+    /// Whether this is the fallback leader
+    fallback: bool,
+}
+
+impl Leader {
+    /// Spawn a leader, and in Wayland sessions wait for it to accept connections
+    async fn spawn(command: &str, display: DisplayMode, config: &SessionConfig) -> Result<Child> {
+        let argv = shell_words::split(command)?;
+        let (program, args) = argv
+            .split_first()
+            .ok_or_else(|| eyre!("the leader command is empty"))?;
+
+        let wayland = match display {
+            DisplayMode::Wayland => {
+                Some(WaylandSocket::prepare(config.wayland_display.as_deref())?)
+            }
+            DisplayMode::X11 => None,
+        };
+
+        let mut child = Command::new(program).args(args).spawn()?;
+
+        // services and apps need the compositor to be accepting connections
+        if let Some(wayland) = wayland {
+            let timeout = Duration::from_secs(config.wayland_timeout);
+            tokio::select! {
+                socket = wayland.wait(timeout) => {
+                    if let Err(e) = socket {
+                        let _ = child.kill().await;
+                        return Err(e);
+                    }
+                }
+                status = child.wait() => {
+                    bail!("the leader exited with {:?} before opening its Wayland socket", status);
+                }
+            }
+        }
+        Ok(child)
+    }
+
+    /// Log how the leader exited, telling a clean exit from a crash
+    pub fn log_exit(&self, status: &ExitStatus) {
+        let uptime = self.started.elapsed();
+        if status.success() {
+            info!(
+                leader = %self.command,
+                code = 0,
+                uptime_secs = uptime.as_secs(),
+                "Leader exited cleanly"
+            );
+        } else {
+            warn!(
+                leader = %self.command,
+                code = ?status.code(),
+                signal = ?status.signal(),
+                core_dumped = status.core_dumped(),
+                uptime_secs = uptime.as_secs(),
+                "Leader crashed"
+            );
+        }
+    }
+}
+
+/// Tell the user the session is running on the fallback leader
+///
+/// The fallback is chosen before d5 serves notifications itself, so this waits in the background
+/// for a notification server to show up.
+fn notify_failsafe(failed: &str) {
+    let body = format!("{failed} failed to start, you are in a failsafe session.");
+    tokio::spawn(async move {
+        let result = async {
+            let conn = zbus::Connection::session().await?;
+            let activatable = DBusProxy::new(&conn)
+                .await?
+                .list_activatable_names()
+                .await?
+                .iter()
+                .any(|n| n.as_str() == NOTIFICATIONS_NAME);
+            if !activatable {
+                wait_for_name(&conn, NOTIFICATIONS_NAME).await?;
+            }
+            let hints = HashMap::from([("urgency", Value::U8(2))]);
+            NotificationsProxy::new(&conn)
+                .await?
+                .notify(
+                    "d5",
+                    0,
+                    "dialog-warning",
+                    "Failsafe session",
+                    &body,
+                    &[],
+                    hints,
+                    0,
+                )
+                .await?;
+            Result::<()>::Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Failed to show the failsafe notification: {:?}", e);
+        }
+    });
+}
+
+/// Starts the leader and decides what replaces it when it crashes
+pub struct LeaderSupervisor<'a> {
+    config: &'a SessionConfig,
+    display: DisplayMode,
+    restarts: u32,
+}
+
+impl<'a> LeaderSupervisor<'a> {
+    pub fn new(config: &'a SessionConfig, display: DisplayMode) -> Self {
+        Self {
+            config,
+            display,
+            restarts: 0,
+        }
+    }
+
+    /// Start the configured leader
+    pub async fn start(&mut self) -> Result<Leader> {
+        self.launch(self.config.leader.clone(), false).await
+    }
+
+    /// Replace a leader that crashed, or fail if there is nothing left to try
+    pub async fn recover(&mut self, crashed: &Leader) -> Result<Leader> {
+        let early = crashed.started.elapsed() < CRASH_WINDOW;
+        match self.next(&crashed.command, crashed.fallback, early) {
+            Some((command, fallback)) => self.launch(command, fallback).await,
+            None => bail!("the leader crashed and will not be restarted"),
+        }
+    }
+
+    /// What to run after `command` failed
+    ///
+    /// A leader that fails right away would only fail again, so it is replaced by the fallback
+    /// without using up restarts. The fallback then gets restarts of its own.
+    fn next(&mut self, command: &str, fallback: bool, early: bool) -> Option<(String, bool)> {
+        if let Some(fallback_leader) = &self.config.fallback_leader {
+            if early && !fallback {
+                warn!("Falling back to {}", fallback_leader);
+                self.restarts = 0;
+                return Some((fallback_leader.clone(), true));
+            }
+        }
+        if self.restarts < self.config.restart_leader {
+            self.restarts += 1;
+            info!(
+                "Restarting the leader ({}/{})",
+                self.restarts, self.config.restart_leader
+            );
+            return Some((command.to_owned(), fallback));
+        }
+        None
+    }
+
+    async fn launch(&mut self, mut command: String, mut fallback: bool) -> Result<Leader> {
+        loop {
+            let started = Instant::now();
+            match Leader::spawn(&command, self.display, self.config).await {
+                Ok(child) => {
+                    if fallback {
+                        notify_failsafe(&self.config.leader);
+                    }
+                    return Ok(Leader {
+                        child,
+                        command,
+                        started,
+                        fallback,
+                    });
+                }
+                Err(e) => {
+                    warn!(leader = %command, "Failed to start the leader: {:?}", e);
+                    match self.next(&command, fallback, true) {
+                        Some(next) => (command, fallback) = next,
+                        None => return Err(e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn early_failures_fall_back_right_away() {
+    let config: crate::config::Config = toml::from_str(
+        r#"
+        [session]
+        leader = "kiri"
+        restart_leader = 1
+        fallback_leader = "weston"
+        "#,
+    )
+    .unwrap();
+    let mut supervisor = LeaderSupervisor::new(&config.session, DisplayMode::Wayland);
+    let kiri = Some(("kiri".to_owned(), false));
+    let weston = Some(("weston".to_owned(), true));
+
+    assert_eq!(supervisor.next("kiri", false, false), kiri);
+    assert_eq!(supervisor.next("kiri", false, true), weston);
+    // the fallback's restarts start over
+    assert_eq!(supervisor.next("weston", true, true), weston);
+    assert_eq!(supervisor.next("weston", true, false), None);
+}
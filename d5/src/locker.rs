@@ -0,0 +1,260 @@
+//! Screen locker
+//!
+//! logind asks the session to lock with the `Lock` signal, on `loginctl lock-session` or the idle
+//! `lock` action. d5 runs the configured locker for it, keeps `LockedHint` up to date, and holds a
+//! delay inhibitor so the locker is up before the system suspends. A crashed locker is started
+//! again, unless it keeps crashing, then the user is told the session could not stay locked.
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use futures::StreamExt;
+use logind_zbus::manager::{InhibitType, ManagerProxy, Mode};
+use logind_zbus::session::SessionProxy;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+use tokio::process::{Child, Command};
+use tracing::{debug, error, info, warn};
+use zbus::zvariant::{OwnedFd, Value};
+
+use crate::dbus::notifier::NotificationsProxy;
+
+/// How long the locker gets to show itself before the system suspends
+const LOCK_GRACE: Duration = Duration::from_millis(500);
+
+/// How long to wait before starting a crashed locker again
+const RESPAWN_DELAY: Duration = Duration::from_secs(1);
+/// The locker is started again at most `RESPAWN_BURST` times within `RESPAWN_INTERVAL`
+const RESPAWN_BURST: usize = 5;
+const RESPAWN_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Locker {
+    command: String,
+    lock_before_sleep: bool,
+    session: SessionProxy<'static>,
+    logind: ManagerProxy<'static>,
+    /// The running locker, while the session is locked
+    child: Option<Child>,
+    /// Delay inhibitor that holds off suspend until the session is locked
+    sleep_lock: Option<OwnedFd>,
+    /// When the locker was started again after crashing, within `RESPAWN_INTERVAL`
+    respawns: VecDeque<Instant>,
+}
+
+impl Locker {
+    pub fn new(
+        command: String,
+        lock_before_sleep: bool,
+        session: SessionProxy<'static>,
+        logind: ManagerProxy<'static>,
+    ) -> Self {
+        Self {
+            command,
+            lock_before_sleep,
+            session,
+            logind,
+            child: None,
+            sleep_lock: None,
+            respawns: VecDeque::with_capacity(RESPAWN_BURST),
+        }
+    }
+
+    /// Follow logind's lock requests and sleep announcements
+    pub async fn watch(mut self) -> zbus::Result<()> {
+        let mut lock = self.session.receive_lock().await?;
+        let mut unlock = self.session.receive_unlock().await?;
+        let mut sleep = self.logind.receive_prepare_for_sleep().await?;
+        if self.lock_before_sleep {
+            self.take_sleep_lock().await;
+        }
+
+        loop {
+            tokio::select! {
+                Some(_) = lock.next() => self.lock().await,
+                Some(_) = unlock.next() => self.unlock(),
+                Some(signal) = sleep.next() => {
+                    if !self.lock_before_sleep {
+                        continue;
+                    }
+                    if signal.args()?.start {
+                        self.lock().await;
+                        tokio::time::sleep(LOCK_GRACE).await;
+                        // let the suspend go ahead
+                        self.sleep_lock = None;
+                    } else {
+                        self.take_sleep_lock().await;
+                    }
+                }
+                status = wait(&mut self.child) => self.exited(status).await,
+            }
+        }
+    }
+
+    async fn take_sleep_lock(&mut self) {
+        let fd = self
+            .logind
+            .inhibit(
+                InhibitType::Sleep,
+                "d5",
+                "Lock the screen before suspend",
+                Mode::Delay,
+            )
+            .await;
+        match fd {
+            Ok(fd) => self.sleep_lock = Some(fd),
+            Err(e) => warn!("Failed to delay suspend for the locker: {:?}", e),
+        }
+    }
+
+    async fn lock(&mut self) {
+        if self.child.is_some() {
+            debug!("The session is already locked");
+            return;
+        }
+        info!("Locking the session");
+        self.respawns.clear();
+        match spawn(&self.command) {
+            Ok(child) => self.child = Some(child),
+            Err(e) => {
+                error!("Failed to start the locker: {:?}", e);
+                return;
+            }
+        }
+        self.set_locked_hint(true).await;
+    }
+
+    /// Ask the locker to unlock, the same way swaylock and gtklock are told to
+    fn unlock(&mut self) {
+        let Some(pid) = self.child.as_ref().and_then(|child| child.id()) else {
+            return;
+        };
+        info!("Unlocking the session");
+        if let Err(e) = kill(Pid::from_raw(pid as i32), Signal::SIGUSR1) {
+            warn!("Failed to signal the locker: {:?}", e);
+        }
+    }
+
+    async fn exited(&mut self, status: io::Result<ExitStatus>) {
+        self.child = None;
+        match status {
+            Ok(status) if status.success() => {
+                info!("The session was unlocked");
+                self.set_locked_hint(false).await;
+            }
+            // the session has to stay locked, so start the locker again
+            status => {
+                error!(
+                    "The locker crashed while the session was locked: {:?}",
+                    status
+                );
+                let now = Instant::now();
+                while let Some(t) = self.respawns.front() {
+                    if now.duration_since(*t) <= RESPAWN_INTERVAL {
+                        break;
+                    }
+                    self.respawns.pop_front();
+                }
+                if self.respawns.len() >= RESPAWN_BURST {
+                    error!(
+                        "The locker crashed too often ({} times in {:?}), giving up",
+                        RESPAWN_BURST, RESPAWN_INTERVAL
+                    );
+                    // nothing covers the screen anymore, do not pretend otherwise
+                    self.set_locked_hint(false).await;
+                    notify_crashed(&self.command).await;
+                    return;
+                }
+                self.respawns.push_back(now);
+                tokio::time::sleep(RESPAWN_DELAY).await;
+                match spawn(&self.command) {
+                    Ok(child) => self.child = Some(child),
+                    Err(e) => error!("Failed to start the locker again: {:?}", e),
+                }
+            }
+        }
+    }
+
+    async fn set_locked_hint(&self, locked: bool) {
+        if let Err(e) = self.session.set_locked_hint(locked).await {
+            warn!("Failed to set LockedHint: {:?}", e);
+        }
+    }
+}
+
+/// Wait for the locker to exit, if it runs
+async fn wait(child: &mut Option<Child>) -> io::Result<ExitStatus> {
+    match child {
+        Some(child) => child.wait().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Tell the user the locker gave up, and the session is not locked
+async fn notify_crashed(command: &str) {
+    let result = async {
+        let conn = zbus::Connection::session().await?;
+        let body = format!("{command} keeps crashing, the session is no longer locked.");
+        let hints = HashMap::from([("urgency", Value::U8(2))]);
+        NotificationsProxy::new(&conn)
+            .await?
+            .notify(
+                "d5",
+                0,
+                "dialog-error",
+                "Screen locker failed",
+                &body,
+                &[],
+                hints,
+                0,
+            )
+            .await
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to show the locker failure notification: {:?}", e);
+    }
+}
+
+fn spawn(command: &str) -> Result<Child> {
+    let argv = shell_words::split(command)?;
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| eyre!("the locker command is empty"))?;
+    Ok(Command::new(program).args(args).spawn()?)
+}
+
+#[tokio::test]
+async fn lock_on_the_session_path_starts_the_locker() {
+    use crate::testbus::{serve_logind, FakeSession, SESSION_PATH};
+    use zbus::SignalContext;
+
+    let bus = private_bus!();
+    let logind = serve_logind(&bus).await;
+    let conn = bus.connect().await;
+    let manager = ManagerProxy::new(&conn).await.unwrap();
+    let session = crate::session::own_session(&conn, &manager).await.unwrap();
+    let locker = Locker::new("sleep 5".to_owned(), false, session, manager);
+    let watching = tokio::spawn(locker.watch());
+
+    let fake = logind
+        .object_server()
+        .interface::<_, FakeSession>(SESSION_PATH)
+        .await
+        .unwrap();
+    let ctxt = SignalContext::new(&logind, SESSION_PATH).unwrap();
+    // the locker may not listen yet, later locks are ignored while it runs
+    let mut locked = false;
+    for _ in 0..50 {
+        FakeSession::lock(&ctxt).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        locked = fake.get().await.locked_hint;
+        if locked {
+            break;
+        }
+    }
+    watching.abort();
+    assert!(locked);
+}
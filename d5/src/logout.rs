@@ -0,0 +1,189 @@
+//! Logout sequence
+//!
+//! Ending the session first asks GNOME clients and logout inhibitors whether it may end,
+//! then stops everything d5 started, in the opposite order it was started in.
+//! Every phase is announced with the `LogoutPhase` signal, so a logout dialog can follow along.
+
+use futures::future::join_all;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::time::Duration;
+use tokio::process::Child;
+use tracing::{debug, info, warn};
+use zbus::{Connection, SignalContext};
+use zbus_systemd::systemd1::ManagerProxy as SystemdManagerProxy;
+
+use crate::autostart::Launched;
+use crate::inhibit::{InhibitorRegistry, INHIBIT_LOGOUT};
+use crate::service::ServiceSet;
+use crate::session::{set_session_state, SessionState, D5, D5_PATH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogoutPhase {
+    /// Asking clients and waiting for logout inhibitors to go away
+    QueryEndSession,
+    /// Telling clients the session ends
+    EndSession,
+    StopAutostart,
+    StopServices,
+    StopLeader,
+    Finished,
+}
+
+impl LogoutPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogoutPhase::QueryEndSession => "query-end-session",
+            LogoutPhase::EndSession => "end-session",
+            LogoutPhase::StopAutostart => "stop-autostart",
+            LogoutPhase::StopServices => "stop-services",
+            LogoutPhase::StopLeader => "stop-leader",
+            LogoutPhase::Finished => "finished",
+        }
+    }
+}
+
+/// What the logout sequence talks to
+pub struct Logout<'a> {
+    /// d5's own connection, where phases are announced
+    pub conn: &'a Connection,
+    /// The connection serving org.gnome.SessionManager
+    pub gnome: &'a Connection,
+    /// How long clients and inhibitors get to let the logout through
+    pub query_timeout: Duration,
+    /// How long the leader gets to exit after SIGTERM
+    pub stop_timeout: Duration,
+}
+
+impl Logout<'_> {
+    async fn announce(&self, phase: LogoutPhase) {
+        debug!("Logout phase: {}", phase.as_str());
+        let result = async {
+            D5::logout_phase(&SignalContext::new(self.conn, D5_PATH)?, phase.as_str()).await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(
+                "Failed to announce logout phase {}: {:?}",
+                phase.as_str(),
+                e
+            );
+        }
+    }
+
+    /// App ID and reason of every logout inhibitor
+    fn logout_inhibitors() -> Vec<(String, String)> {
+        InhibitorRegistry::fetch()
+            .inhibitors
+            .values()
+            .filter(|i| i.flags & INHIBIT_LOGOUT != 0)
+            .map(|i| (i.app_id.clone(), i.reason.clone()))
+            .collect()
+    }
+
+    /// Ask whether the session may end
+    ///
+    /// Returns whoever is still in the way once the timeout passes, with their reasons.
+    pub async fn query(&self) -> Vec<(String, String)> {
+        set_session_state(self.conn, SessionState::QueryEnding).await;
+        self.announce(LogoutPhase::QueryEndSession).await;
+
+        let deadline = tokio::time::Instant::now() + self.query_timeout;
+        let mut blockers =
+            match crate::gnome::query_end_session(self.gnome, 0, self.query_timeout).await {
+                Ok(refusals) => refusals,
+                Err(e) => {
+                    warn!("Failed to query GNOME clients: {:?}", e);
+                    vec![]
+                }
+            };
+
+        // inhibitors get until the deadline to be released
+        loop {
+            let changed = InhibitorRegistry::fetch().changed.listen();
+            let inhibitors = Self::logout_inhibitors();
+            if inhibitors.is_empty() || tokio::time::timeout_at(deadline, changed).await.is_err() {
+                blockers.extend(inhibitors);
+                break;
+            }
+        }
+        blockers
+    }
+
+    /// Give up on ending the session
+    pub async fn cancel(&self, blockers: Vec<(String, String)>) {
+        for (app_id, reason) in &blockers {
+            info!("Logout blocked by {}: {}", app_id, reason);
+        }
+        if let Err(e) = crate::gnome::cancel_end_session(self.gnome).await {
+            warn!("Failed to cancel the logout for GNOME clients: {:?}", e);
+        }
+        let result = async {
+            D5::logout_cancelled(&SignalContext::new(self.conn, D5_PATH)?, blockers).await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Failed to announce the cancelled logout: {:?}", e);
+        }
+        set_session_state(self.conn, SessionState::Running).await;
+    }
+
+    /// Stop everything d5 started, newest first
+    ///
+    /// `leader` is `None` if it already exited.
+    pub async fn run(
+        &self,
+        systemd: &SystemdManagerProxy<'_>,
+        autostart: Vec<Launched>,
+        services: &ServiceSet,
+        leader: Option<&mut Child>,
+    ) {
+        set_session_state(self.conn, SessionState::Ending).await;
+        self.announce(LogoutPhase::EndSession).await;
+        if let Err(e) = crate::gnome::end_session(self.gnome, 0, self.query_timeout).await {
+            warn!("Failed to end the session for GNOME clients: {:?}", e);
+        }
+        if let Err(e) = crate::gnome::stop_clients(self.gnome).await {
+            warn!("Failed to stop GNOME clients: {:?}", e);
+        }
+
+        self.announce(LogoutPhase::StopAutostart).await;
+        // apps get their grace period side by side, not one after the other
+        let stopped = autostart
+            .into_iter()
+            .rev()
+            .map(|app| app.stop(systemd, self.stop_timeout));
+        for result in join_all(stopped).await {
+            if let Err(e) = result {
+                debug!("Failed to stop autostart app: {:?}", e);
+            }
+        }
+
+        self.announce(LogoutPhase::StopServices).await;
+        services.stop_all(systemd).await;
+
+        if let Some(leader) = leader {
+            self.announce(LogoutPhase::StopLeader).await;
+            if let Err(e) = stop_leader(leader, self.stop_timeout).await {
+                warn!("Failed to stop the leader: {:?}", e);
+            }
+        }
+
+        self.announce(LogoutPhase::Finished).await;
+    }
+}
+
+/// SIGTERM the leader, and SIGKILL it if it is still around after `timeout`
+async fn stop_leader(leader: &mut Child, timeout: Duration) -> color_eyre::Result<()> {
+    if let Some(pid) = leader.id() {
+        kill(Pid::from_raw(pid as i32), Signal::SIGTERM)?;
+    }
+    match tokio::time::timeout(timeout, leader.wait()).await {
+        Ok(status) => info!("Leader exited with {}", status?),
+        Err(_) => {
+            warn!("Leader did not exit within {:?}, killing it", timeout);
+            leader.kill().await?;
+        }
+    }
+    Ok(())
+}
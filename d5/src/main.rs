@@ -1,13 +1,31 @@
 //! d5 - the Kiri session manager
 //! This is the main entry point for the d5 binary.
 //! It does some fancy dbus stuff and then starts the main loop.
+// first, so its macros reach every module
+#[cfg(test)]
+#[macro_use]
+mod testbus;
+mod autostart;
+mod check;
 mod cli;
 mod config;
+mod dbus;
 mod env;
+mod gnome;
+mod idle;
+mod inhibit;
 mod interface;
+mod leader;
+mod locker;
+mod logout;
 mod notify;
 mod proc;
+mod reload;
+mod scope;
+mod service;
 mod session;
+mod util;
+mod wayland;
 
 use color_eyre::Result;
 use tracing::{debug, log};
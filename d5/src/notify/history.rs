@@ -0,0 +1,475 @@
+//! Notification history
+//!
+//! d5 records the notifications it sees go by on the session bus in
+//! `$XDG_STATE_HOME/d5/notifications`, so whatever was missed during do-not-disturb, or expired
+//! before anyone looked, can still be looked up on `com.fyralabs.d5.NotificationHistory`.
+
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use directories::BaseDirs;
+use event_listener::Event;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, warn};
+use zbus::{dbus_interface, fdo, Connection, SignalContext};
+
+use crate::config::HistoryConfig;
+use crate::notify::server::CloseReason;
+use crate::notify::Notification;
+
+pub const HISTORY_PATH: &str = "/com/fyralabs/d5/notifications/history";
+
+/// History ID, notification ID, app name, app icon, summary, body, urgency,
+/// received and closed as Unix timestamps, close reason and whether it was read
+///
+/// `closed` and the close reason are 0 while the notification is still open.
+pub type HistoryRow = (
+    u64,
+    u32,
+    String,
+    String,
+    String,
+    String,
+    u8,
+    i64,
+    i64,
+    u32,
+    bool,
+);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    /// ID the notification server gave the notification
+    pub notification_id: u32,
+    pub app_name: String,
+    pub app_icon: String,
+    pub summary: String,
+    pub body: String,
+    pub urgency: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    pub received: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closed: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub close_reason: Option<CloseReason>,
+    #[serde(default)]
+    pub read: bool,
+}
+
+impl HistoryEntry {
+    fn row(&self) -> HistoryRow {
+        (
+            self.id,
+            self.notification_id,
+            self.app_name.clone(),
+            self.app_icon.clone(),
+            self.summary.clone(),
+            self.body.clone(),
+            self.urgency,
+            self.received.timestamp(),
+            self.closed.map(|t| t.timestamp()).unwrap_or(0),
+            self.close_reason.map(|r| r as u32).unwrap_or(0),
+            self.read,
+        )
+    }
+
+    fn matches(&self, query: &str) -> bool {
+        [&self.app_name, &self.summary, &self.body]
+            .iter()
+            .any(|field| field.to_lowercase().contains(query))
+    }
+}
+
+/// The on-disk file format
+#[derive(Default, Serialize, Deserialize)]
+struct HistoryFile {
+    next_id: u64,
+    entries: Vec<HistoryEntry>,
+}
+
+pub struct History {
+    file: PathBuf,
+    config: HistoryConfig,
+    next_id: u64,
+    /// Oldest first
+    entries: Vec<HistoryEntry>,
+    /// Whether there are changes the file does not have yet
+    dirty: bool,
+    /// Notified whenever an entry is added, changed or removed
+    pub changed: Event,
+}
+
+impl History {
+    /// `$XDG_STATE_HOME/d5/notifications`
+    pub fn default_dir() -> Result<PathBuf> {
+        let base = BaseDirs::new().ok_or_else(|| eyre!("could not find the home directory"))?;
+        let state = base
+            .state_dir()
+            .ok_or_else(|| eyre!("could not find the state directory"))?;
+        Ok(state.join("d5").join("notifications"))
+    }
+
+    /// Load the history kept in `dir`, or start an empty one
+    pub fn open(dir: &Path, config: HistoryConfig) -> Result<Self> {
+        let file = dir.join("history.json");
+        let HistoryFile { next_id, entries } = match std::fs::read(&file) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => HistoryFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut history = Self {
+            file,
+            config,
+            next_id,
+            entries,
+            dirty: false,
+            changed: Event::new(),
+        };
+        history.prune(Utc::now());
+        Ok(history)
+    }
+
+    /// The history as it should be on disk, if it changed since this was last asked
+    fn unsaved(&mut self) -> Option<Result<Vec<u8>>> {
+        if !std::mem::take(&mut self.dirty) {
+            return None;
+        }
+        Some(
+            serde_json::to_vec(&HistoryFile {
+                next_id: self.next_id,
+                entries: self.entries.clone(),
+            })
+            .map_err(Into::into),
+        )
+    }
+
+    /// Apply the retention policy, and tell everyone, the saver included
+    fn commit(&mut self, now: DateTime<Utc>) {
+        self.prune(now);
+        self.dirty = true;
+        self.changed.notify(usize::MAX);
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>) {
+        if self.config.max_age_days > 0 {
+            let cutoff = now - Duration::days(self.config.max_age_days.into());
+            self.entries.retain(|e| e.received >= cutoff);
+        }
+        let excess = self.entries.len().saturating_sub(self.config.max_entries);
+        self.entries.drain(..excess);
+    }
+
+    /// Record a notification the server accepted as `notification_id`
+    pub fn record(&mut self, n: &Notification, notification_id: u32, at: DateTime<Utc>) {
+        // transient notifications ask not to be kept around
+        if n.is_transient() {
+            return;
+        }
+
+        let replaced = self
+            .entries
+            .iter()
+            .rposition(|e| e.notification_id == n.replaces_id && e.closed.is_none());
+        let index = match replaced {
+            Some(index) if n.replaces_id != 0 => index,
+            _ => {
+                self.next_id += 1;
+                self.entries.push(HistoryEntry {
+                    id: self.next_id,
+                    notification_id,
+                    app_name: String::new(),
+                    app_icon: String::new(),
+                    summary: String::new(),
+                    body: String::new(),
+                    urgency: 0,
+                    category: None,
+                    received: at,
+                    closed: None,
+                    close_reason: None,
+                    read: false,
+                });
+                self.entries.len() - 1
+            }
+        };
+        let entry = &mut self.entries[index];
+        debug!(
+            "Recording notification {} from {}",
+            notification_id, n.app_id
+        );
+        entry.notification_id = notification_id;
+        entry.app_name = n.app_id.clone();
+        entry.app_icon = n.app_icon.clone();
+        entry.summary = n.summary.clone();
+        entry.body = n.body.clone();
+        entry.urgency = n.hints.urgency() as u8;
+        entry.category = n.hints.category.clone();
+        entry.received = at;
+        entry.read = false;
+        self.commit(at);
+    }
+
+    /// Record that the server closed a notification
+    pub fn record_closed(&mut self, notification_id: u32, reason: CloseReason, at: DateTime<Utc>) {
+        let Some(entry) = self
+            .entries
+            .iter_mut()
+            .rev()
+            .find(|e| e.notification_id == notification_id && e.closed.is_none())
+        else {
+            return;
+        };
+        entry.closed = Some(at);
+        entry.close_reason = Some(reason);
+        self.commit(at);
+    }
+
+    /// Entries newest first
+    pub fn list(&self, unread_only: bool) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(move |e| !unread_only || !e.read)
+    }
+
+    /// Entries whose app name, summary or body contain `query`, ignoring case, newest first
+    pub fn search(&self, query: &str) -> impl Iterator<Item = &HistoryEntry> {
+        let query = query.to_lowercase();
+        self.list(false).filter(move |e| e.matches(&query))
+    }
+
+    /// Mark entries read, all of them if `ids` is empty
+    pub fn mark_read(&mut self, ids: &[u64]) {
+        for entry in &mut self.entries {
+            if ids.is_empty() || ids.contains(&entry.id) {
+                entry.read = true;
+            }
+        }
+        self.commit(Utc::now());
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.commit(Utc::now());
+    }
+
+    pub fn unread(&self) -> u32 {
+        self.entries.iter().filter(|e| !e.read).count() as u32
+    }
+}
+
+/// `com.fyralabs.d5.NotificationHistory`
+pub struct NotificationHistory {
+    pub history: Arc<Mutex<History>>,
+}
+
+#[dbus_interface(name = "com.fyralabs.d5.NotificationHistory")]
+impl NotificationHistory {
+    /// Recorded notifications, newest first
+    fn list(&self, unread_only: bool) -> Vec<HistoryRow> {
+        self.history
+            .lock()
+            .list(unread_only)
+            .map(HistoryEntry::row)
+            .collect()
+    }
+
+    /// Notifications whose app name, summary or body contain `query`, newest first
+    fn search(&self, query: &str) -> Vec<HistoryRow> {
+        self.history
+            .lock()
+            .search(query)
+            .map(HistoryEntry::row)
+            .collect()
+    }
+
+    /// Mark notifications read, every one of them if `ids` is empty
+    fn mark_read(&self, ids: Vec<u64>) {
+        self.history.lock().mark_read(&ids);
+    }
+
+    /// Forget every recorded notification
+    fn clear(&self) {
+        self.history.lock().clear();
+    }
+
+    #[dbus_interface(property)]
+    fn unread_count(&self) -> u32 {
+        self.history.lock().unread()
+    }
+
+    /// The history changed
+    #[dbus_interface(signal)]
+    async fn changed(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+}
+
+fn write(file: &Path, data: &[u8]) -> Result<()> {
+    let dir = file.parent().expect("the history file is in a directory");
+    std::fs::create_dir_all(dir)?;
+    // never leave a half written history behind
+    let tmp = file.with_extension("json.tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, file)?;
+    Ok(())
+}
+
+/// Save the history whenever it changes
+///
+/// The file is written off the async workers and outside the lock. Changes made while a save is
+/// running go into the next one.
+pub fn keep_saved(history: Arc<Mutex<History>>) {
+    tokio::spawn(async move {
+        loop {
+            let changed = history.lock().changed.listen();
+            let unsaved = {
+                let mut history = history.lock();
+                history.unsaved().map(|data| (history.file.clone(), data))
+            };
+            if let Some((file, data)) = unsaved {
+                let result: Result<()> = async {
+                    let data = data?;
+                    tokio::task::spawn_blocking(move || write(&file, &data)).await?
+                }
+                .await;
+                if let Err(e) = result {
+                    warn!("Failed to save the notification history: {:?}", e);
+                }
+            }
+            changed.await;
+        }
+    });
+}
+
+/// Serve the history on `conn`, and keep `UnreadCount` up to date
+pub async fn serve(conn: &Connection, history: Arc<Mutex<History>>) -> fdo::Result<()> {
+    conn.object_server()
+        .at(
+            HISTORY_PATH,
+            NotificationHistory {
+                history: history.clone(),
+            },
+        )
+        .await?;
+
+    let conn = conn.clone();
+    tokio::spawn(async move {
+        let result: zbus::Result<()> = async {
+            let iface = conn
+                .object_server()
+                .interface::<_, NotificationHistory>(HISTORY_PATH)
+                .await?;
+            loop {
+                let changed = history.lock().changed.listen();
+                changed.await;
+                let ctxt = iface.signal_context();
+                NotificationHistory::changed(ctxt).await?;
+                iface.get().await.unread_count_changed(ctxt).await?;
+            }
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Stopped announcing notification history changes: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+fn history(name: &str, max_entries: usize) -> History {
+    let dir = std::env::temp_dir().join(format!("d5-history-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = HistoryConfig {
+        enabled: true,
+        max_entries,
+        max_age_days: 30,
+    };
+    History::open(&dir, config).unwrap()
+}
+
+#[cfg(test)]
+fn notification(app: &str, summary: &str, replaces_id: u32) -> Notification {
+    Notification {
+        app_id: app.to_owned(),
+        summary: summary.to_owned(),
+        replaces_id,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn history_is_saved_and_loaded() {
+    let mut h = history("saved", 10);
+    let now = Utc::now();
+    h.record(&notification("mail", "New mail", 0), 1, now);
+    h.record_closed(1, CloseReason::Dismissed, now);
+    h.record(&notification("chat", "Hi", 0), 2, now);
+    let data = h.unsaved().unwrap().unwrap();
+    write(&h.file, &data).unwrap();
+    assert!(h.unsaved().is_none());
+
+    let dir = h.file.parent().unwrap().to_owned();
+    let loaded = History::open(&dir, h.config.clone()).unwrap();
+    let entries = loaded.list(false).collect::<Vec<_>>();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].summary, "Hi");
+    assert_eq!(entries[1].close_reason, Some(CloseReason::Dismissed));
+    assert_eq!(loaded.next_id, 2);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn replacing_updates_the_open_entry() {
+    let mut h = history("replace", 10);
+    let now = Utc::now();
+    h.record(&notification("player", "Song A", 0), 5, now);
+    h.mark_read(&[]);
+    h.record(&notification("player", "Song B", 5), 5, now);
+
+    let entries = h.list(false).collect::<Vec<_>>();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].summary, "Song B");
+    assert!(!entries[0].read);
+}
+
+#[test]
+fn retention_drops_old_entries() {
+    let mut h = history("retention", 2);
+    let now = Utc::now();
+    h.record(
+        &notification("a", "too old", 0),
+        1,
+        now - Duration::days(31),
+    );
+    h.record(&notification("a", "one", 0), 2, now);
+    h.record(&notification("a", "two", 0), 3, now);
+    h.record(&notification("a", "three", 0), 4, now);
+
+    let summaries = h
+        .list(false)
+        .map(|e| e.summary.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(summaries, ["three", "two"]);
+}
+
+#[test]
+fn search_and_mark_read() {
+    let mut h = history("search", 10);
+    let now = Utc::now();
+    h.record(&notification("Firefox", "Download finished", 0), 1, now);
+    h.record(&notification("Mail", "Invoice", 0), 2, now);
+    assert_eq!(h.unread(), 2);
+
+    let found = h.search("firefox").map(|e| e.id).collect::<Vec<_>>();
+    assert_eq!(found, [1]);
+    h.mark_read(&found);
+    let unread = h.list(true).map(|e| e.summary.as_str()).collect::<Vec<_>>();
+    assert_eq!(unread, ["Invoice"]);
+
+    h.clear();
+    assert_eq!(h.list(false).count(), 0);
+}
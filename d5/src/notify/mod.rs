@@ -0,0 +1,362 @@
+// xdg notifications
+pub mod history;
+pub mod server;
+
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use futures::TryStreamExt;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::debug;
+use zbus::fdo::MonitoringProxy;
+use zbus::zvariant::OwnedValue;
+use zbus::MessageStream;
+
+use crate::notify::history::History;
+use crate::notify::server::CloseReason;
+
+#[derive(Debug, Default, Serialize, Deserialize, zbus::zvariant::Type)]
+pub struct Notification {
+    pub app_id: String,
+    pub replaces_id: u32,
+    pub app_icon: String,
+    pub summary: String,
+    pub body: String,
+    pub actions: Vec<String>,
+    // variant of u8 or u32
+    pub hints: NotificationHint,
+    pub expire_timeout: i32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Urgency {
+    Low = 0,
+    #[default]
+    Normal = 1,
+    High = 2,
+}
+
+impl From<u8> for Urgency {
+    /// Unknown levels are treated as normal
+    fn from(level: u8) -> Self {
+        match level {
+            0 => Urgency::Low,
+            2 => Urgency::High,
+            _ => Urgency::Normal,
+        }
+    }
+}
+
+impl Urgency {
+    /// Urgency of a raw hint dictionary
+    pub fn from_hints(hints: &HashMap<String, OwnedValue>) -> Self {
+        hints
+            .get("urgency")
+            .and_then(|v| u8::try_from(v).ok())
+            .map(Urgency::from)
+            .unwrap_or_default()
+    }
+}
+
+/// Raw image, as sent in the `image-data` hint
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    zbus::zvariant::Type,
+    zbus::zvariant::OwnedValue,
+)]
+pub struct ImageData {
+    pub width: i32,
+    pub height: i32,
+    pub rowstride: i32,
+    pub has_alpha: bool,
+    pub bits_per_sample: i32,
+    pub channels: i32,
+    pub data: Vec<u8>,
+}
+
+/// Hints from the notification spec
+///
+/// Every hint is optional, and a hint with the wrong type is skipped rather than failing the whole
+/// notification.
+#[derive(Default, Debug, zbus::zvariant::Type, zbus::zvariant::SerializeDict)]
+#[zvariant(signature = "a{sv}")]
+pub struct NotificationHint {
+    #[zvariant(rename = "sender-pid")]
+    pub sender_pid: Option<i64>,
+    pub urgency: Option<u8>,
+    pub transient: Option<bool>,
+    pub category: Option<String>,
+    pub resident: Option<bool>,
+    #[zvariant(rename = "action-icons")]
+    pub action_icons: Option<bool>,
+    #[zvariant(rename = "desktop-entry")]
+    pub desktop_entry: Option<String>,
+
+    // images, newest name first
+    #[zvariant(rename = "image-data")]
+    pub image_data: Option<ImageData>,
+    #[zvariant(rename = "image_data")]
+    pub image_data_legacy: Option<ImageData>,
+    pub icon_data: Option<ImageData>,
+    #[zvariant(rename = "image-path")]
+    pub image_path: Option<String>,
+
+    // sounds
+    #[zvariant(rename = "sound-file")]
+    pub sound_file: Option<String>,
+    #[zvariant(rename = "sound-name")]
+    pub sound_name: Option<String>,
+    #[zvariant(rename = "suppress-sound")]
+    pub suppress_sound: Option<bool>,
+
+    // where to point the notification at
+    pub x: Option<i32>,
+    pub y: Option<i32>,
+}
+
+impl<'de> Deserialize<'de> for NotificationHint {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashMap::<String, OwnedValue>::deserialize(deserializer).map(|hints| Self::from(&hints))
+    }
+}
+
+impl From<&HashMap<String, OwnedValue>> for NotificationHint {
+    fn from(hints: &HashMap<String, OwnedValue>) -> Self {
+        fn get<T: TryFrom<OwnedValue>>(
+            hints: &HashMap<String, OwnedValue>,
+            key: &str,
+        ) -> Option<T> {
+            hints.get(key).and_then(|v| T::try_from(v.clone()).ok())
+        }
+        Self {
+            sender_pid: get(hints, "sender-pid"),
+            urgency: get(hints, "urgency"),
+            transient: get(hints, "transient"),
+            category: get(hints, "category"),
+            resident: get(hints, "resident"),
+            action_icons: get(hints, "action-icons"),
+            desktop_entry: get(hints, "desktop-entry"),
+            image_data: get(hints, "image-data"),
+            image_data_legacy: get(hints, "image_data"),
+            icon_data: get(hints, "icon_data"),
+            image_path: get(hints, "image-path"),
+            sound_file: get(hints, "sound-file"),
+            sound_name: get(hints, "sound-name"),
+            suppress_sound: get(hints, "suppress-sound"),
+            x: get(hints, "x"),
+            y: get(hints, "y"),
+        }
+    }
+}
+
+impl NotificationHint {
+    pub fn urgency(&self) -> Urgency {
+        self.urgency.map(Urgency::from).unwrap_or_default()
+    }
+
+    /// The image to show, whichever version of the spec the client follows
+    pub fn image(&self) -> Option<&ImageData> {
+        self.image_data
+            .as_ref()
+            .or(self.image_data_legacy.as_ref())
+            .or(self.icon_data.as_ref())
+    }
+
+    /// Where the notification points at, if the client sent both coordinates
+    pub fn position(&self) -> Option<(i32, i32)> {
+        Some((self.x?, self.y?))
+    }
+}
+
+impl Notification {
+    pub fn display(&self) -> String {
+        format!("{}: {}", self.summary, self.body)
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.hints.transient.unwrap_or(false)
+    }
+
+    pub fn is_resident(&self) -> bool {
+        self.hints.resident.unwrap_or(false)
+    }
+}
+
+/// Watch notifications go by on the session bus and record them in `history`
+///
+/// This works with any notification server, d5's own included. The monitor connection cannot be
+/// used for anything else.
+pub async fn listen(history: Arc<Mutex<History>>) -> Result<()> {
+    // monitor notifications, do not replace existing daemon
+    let conn = zbus::Connection::session().await?;
+    let mon = MonitoringProxy::builder(&conn)
+        .destination("org.freedesktop.DBus")?
+        .interface("org.freedesktop.DBus.Monitoring")?
+        .path("/org/freedesktop/DBus")?
+        .build()
+        .await?;
+
+    // the reply to Notify carries the notification's id
+    let match_rules = vec![
+        "type='method_call',interface='org.freedesktop.Notifications',member='Notify'",
+        "type='method_return',sender='org.freedesktop.Notifications'",
+        "type='signal',interface='org.freedesktop.Notifications',member='NotificationClosed'",
+    ];
+    mon.become_monitor(match_rules.as_slice(), 0).await?;
+
+    // Notify calls waiting for their reply, by caller and serial
+    let mut pending: HashMap<(String, u32), (Notification, DateTime<Utc>)> = HashMap::new();
+    let mut stream = MessageStream::from(&mon.connection().clone());
+    while let Some(msg) = stream.try_next().await? {
+        let header = msg.header()?;
+        match msg.message_type() {
+            zbus::MessageType::MethodCall => {
+                let (Some(sender), Some(serial)) =
+                    (header.sender()?, msg.primary_header().serial_num())
+                else {
+                    continue;
+                };
+                match msg.body::<Notification>() {
+                    Ok(notification) => {
+                        let now = Utc::now();
+                        // calls that failed never get a reply
+                        pending.retain(|_, (_, at)| now - *at < chrono::Duration::minutes(1));
+                        pending.insert((sender.to_string(), *serial), (notification, now));
+                    }
+                    Err(e) => debug!("Failed to parse a notification from {}: {:?}", sender, e),
+                }
+            }
+            zbus::MessageType::MethodReturn => {
+                let (Some(destination), Some(serial)) = (header.destination()?, msg.reply_serial())
+                else {
+                    continue;
+                };
+                let Some((notification, at)) = pending.remove(&(destination.to_string(), serial))
+                else {
+                    continue;
+                };
+                if let Ok(id) = msg.body::<u32>() {
+                    history.lock().record(&notification, id, at);
+                }
+            }
+            zbus::MessageType::Signal => {
+                if let Ok((id, reason)) = msg.body::<(u32, u32)>() {
+                    history
+                        .lock()
+                        .record_closed(id, CloseReason::from(reason), Utc::now());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "Runs indefinitely, integration test for notifications"]
+async fn test() {
+    color_eyre::install().unwrap();
+    let dir = std::env::temp_dir().join("d5-notifications");
+    let history = History::open(&dir, crate::config::HistoryConfig::default()).unwrap();
+    listen(Arc::new(Mutex::new(history))).await.unwrap();
+}
+
+/// A serialized `Notify` call
+///
+/// These are built by hand, modelled on what notify-send, Firefox and friends send; the sender,
+/// pids and images are made up.
+#[cfg(test)]
+fn fixture(name: &str) -> Notification {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/notify")
+        .join(format!("{name}.bin"));
+    let bytes = std::fs::read(path).unwrap();
+    // SAFETY: the fixtures carry no file descriptors
+    let msg = unsafe { zbus::Message::from_bytes(bytes, vec![]) }.unwrap();
+    assert_eq!(msg.member().unwrap().as_str(), "Notify");
+    msg.body::<Notification>().unwrap()
+}
+
+#[test]
+fn libnotify_hints() {
+    let n = fixture("libnotify");
+    assert_eq!(n.app_id, "notify-test");
+    assert_eq!(n.display(), "Hello: This is a test notification");
+    assert_eq!(n.hints.sender_pid, Some(4242));
+    assert_eq!(n.hints.urgency(), Urgency::Normal);
+    assert!(n.hints.image().is_none());
+    assert_eq!(n.expire_timeout, -1);
+}
+
+#[test]
+fn notify_send_hints() {
+    let n = fixture("notify-send-critical");
+    assert_eq!(n.app_icon, "drive-harddisk");
+    assert_eq!(n.hints.urgency(), Urgency::High);
+    assert_eq!(n.hints.category.as_deref(), Some("transfer.error"));
+    assert_eq!(n.expire_timeout, 0);
+
+    let n = fixture("notify-send-hints");
+    assert_eq!(n.hints.urgency(), Urgency::Low);
+    assert!(n.is_transient());
+    assert_eq!(n.hints.sound_name.as_deref(), Some("message-new-instant"));
+    assert_eq!(n.hints.suppress_sound, Some(false));
+    assert_eq!(n.hints.position(), Some((1820, 40)));
+}
+
+#[test]
+fn firefox_hints() {
+    let n = fixture("firefox");
+    assert_eq!(n.actions, ["default", "Activate"]);
+    assert_eq!(n.hints.desktop_entry.as_deref(), Some("firefox"));
+    assert_eq!(n.hints.urgency(), Urgency::Normal);
+
+    let image = n.hints.image().unwrap();
+    assert_eq!((image.width, image.height, image.channels), (2, 2, 4));
+    assert!(image.has_alpha);
+    assert_eq!(image.data.len(), (image.rowstride * image.height) as usize);
+}
+
+#[test]
+fn electron_hints() {
+    let n = fixture("electron");
+    assert_eq!(n.replaces_id, 7);
+    assert_eq!(n.hints.desktop_entry.as_deref(), Some("slack"));
+    assert_eq!(
+        n.hints.image_path.as_deref(),
+        Some("file:///tmp/.org.chromium.Chromium.x8Yq2c/avatar.png")
+    );
+    assert_eq!(n.hints.suppress_sound, Some(true));
+}
+
+#[test]
+fn resident_hints() {
+    let n = fixture("music-player");
+    assert!(n.is_resident());
+    assert_eq!(n.hints.action_icons, Some(true));
+    assert_eq!(
+        n.hints.sound_file.as_deref(),
+        Some("/usr/share/sounds/freedesktop/stereo/bell.oga")
+    );
+    assert!(n.hints.sender_pid.is_none());
+    assert!(n.hints.position().is_none());
+}
+
+#[test]
+fn legacy_hints() {
+    // an old client: deprecated icon_data, and urgency sent with the wrong type
+    let n = fixture("legacy-icon-data");
+    let image = n.hints.image().unwrap();
+    assert_eq!((image.width, image.height, image.channels), (2, 1, 3));
+    assert!(!image.has_alpha);
+    assert!(n.hints.urgency.is_none());
+    assert_eq!(n.hints.urgency(), Urgency::Normal);
+}
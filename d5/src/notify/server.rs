@@ -0,0 +1,438 @@
+//! Built-in notification server
+//!
+//! When the session has no notification daemon, d5 serves `org.freedesktop.Notifications` itself.
+//! It keeps track of notifications and their expiry, and leaves drawing them to a frontend:
+//! either a command fed JSON events on stdin, or whatever follows the signals on
+//! `com.fyralabs.d5.Notifications`.
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use zbus::fdo::{self, DBusProxy, RequestNameFlags, RequestNameReply};
+use zbus::zvariant::OwnedValue;
+use zbus::{dbus_interface, Connection, ConnectionBuilder, SignalContext};
+
+use crate::notify::Urgency;
+
+pub const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+pub const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+
+/// How long notifications stay up when the sender leaves it to the server
+const DEFAULT_EXPIRE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why a notification was closed, as sent with `NotificationClosed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CloseReason {
+    Expired = 1,
+    Dismissed = 2,
+    Closed = 3,
+    Undefined = 4,
+}
+
+impl From<u32> for CloseReason {
+    /// Reasons servers make up are undefined too
+    fn from(reason: u32) -> Self {
+        match reason {
+            1 => CloseReason::Expired,
+            2 => CloseReason::Dismissed,
+            3 => CloseReason::Closed,
+            _ => CloseReason::Undefined,
+        }
+    }
+}
+
+/// A notification that is currently shown
+pub struct Shown {
+    pub app_name: String,
+    pub app_icon: String,
+    pub summary: String,
+    pub body: String,
+    /// Action keys and labels
+    pub actions: Vec<(String, String)>,
+    pub hints: HashMap<String, OwnedValue>,
+    expiry: Option<JoinHandle<()>>,
+}
+
+impl Shown {
+    fn urgency(&self) -> Urgency {
+        Urgency::from_hints(&self.hints)
+    }
+}
+
+/// A frontend command, spawned on the first event and again if it goes away
+struct Frontend {
+    command: String,
+    process: Option<(Child, ChildStdin)>,
+}
+
+impl Frontend {
+    fn spawn(&self) -> Result<(Child, ChildStdin)> {
+        let argv = shell_words::split(&self.command)?;
+        let (program, args) = argv
+            .split_first()
+            .ok_or_else(|| eyre!("the notification frontend command is empty"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| eyre!("the notification frontend has no stdin"))?;
+        Ok((child, stdin))
+    }
+
+    async fn send(&mut self, event: &serde_json::Value) {
+        let line = format!("{event}\n");
+        // one retry, for a frontend that exited since the last event
+        for _ in 0..2 {
+            if self.process.is_none() {
+                match self.spawn() {
+                    Ok(process) => self.process = Some(process),
+                    Err(e) => {
+                        warn!("Failed to start the notification frontend: {:?}", e);
+                        return;
+                    }
+                }
+            }
+            if let Some((_, stdin)) = &mut self.process {
+                match stdin.write_all(line.as_bytes()).await {
+                    Ok(()) => return,
+                    Err(e) => debug!("Notification frontend went away: {:?}", e),
+                }
+            }
+            self.process = None;
+        }
+    }
+}
+
+/// `org.freedesktop.Notifications`
+pub struct NotificationServer {
+    pub notifications: BTreeMap<u32, Shown>,
+    next_id: u32,
+    frontend: Option<Frontend>,
+}
+
+impl NotificationServer {
+    pub fn new(frontend: Option<String>) -> Self {
+        Self {
+            notifications: BTreeMap::new(),
+            next_id: 1,
+            frontend: frontend.map(|command| Frontend {
+                command,
+                process: None,
+            }),
+        }
+    }
+
+    async fn show(&mut self, ctxt: &SignalContext<'_>, id: u32) -> zbus::Result<()> {
+        let Some(n) = self.notifications.get(&id) else {
+            return Ok(());
+        };
+        if let Some(frontend) = &mut self.frontend {
+            let event = json!({
+                "event": "show",
+                "id": id,
+                "app_name": n.app_name,
+                "app_icon": n.app_icon,
+                "summary": n.summary,
+                "body": n.body,
+                "actions": n.actions,
+                "urgency": n.urgency() as u8,
+            });
+            frontend.send(&event).await;
+        }
+        NotificationFrontend::show(
+            ctxt,
+            id,
+            &n.app_name,
+            &n.app_icon,
+            &n.summary,
+            &n.body,
+            n.actions.clone(),
+            n.urgency() as u8,
+        )
+        .await
+    }
+
+    /// Close a notification and tell the sender and the frontend why
+    pub async fn close(
+        &mut self,
+        ctxt: &SignalContext<'_>,
+        id: u32,
+        reason: CloseReason,
+    ) -> zbus::Result<bool> {
+        let Some(n) = self.notifications.remove(&id) else {
+            return Ok(false);
+        };
+        if let Some(expiry) = n.expiry {
+            expiry.abort();
+        }
+        debug!("Closing notification {} ({:?})", id, reason);
+        if let Some(frontend) = &mut self.frontend {
+            frontend
+                .send(&json!({ "event": "close", "id": id, "reason": reason as u32 }))
+                .await;
+        }
+        NotificationFrontend::hide(ctxt, id, reason as u32).await?;
+        Self::notification_closed(ctxt, id, reason as u32).await?;
+        Ok(true)
+    }
+
+    /// Invoke an action of a notification, which closes it
+    pub async fn invoke(
+        &mut self,
+        ctxt: &SignalContext<'_>,
+        id: u32,
+        action_key: &str,
+    ) -> zbus::Result<bool> {
+        let known = self
+            .notifications
+            .get(&id)
+            .map(|n| n.actions.iter().any(|(key, _)| key == action_key))
+            .unwrap_or(false);
+        if !known {
+            return Ok(false);
+        }
+        Self::action_invoked(ctxt, id, action_key).await?;
+        self.close(ctxt, id, CloseReason::Dismissed).await
+    }
+}
+
+/// Close notification `id` once `timeout` passes
+fn expire(conn: Connection, id: u32, timeout: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        let result = async {
+            let iface = conn
+                .object_server()
+                .interface::<_, NotificationServer>(NOTIFICATIONS_PATH)
+                .await?;
+            let mut server = iface.get_mut().await;
+            // the timer must not abort itself
+            if let Some(n) = server.notifications.get_mut(&id) {
+                n.expiry = None;
+            }
+            server
+                .close(iface.signal_context(), id, CloseReason::Expired)
+                .await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Failed to expire notification {}: {:?}", id, e);
+        }
+    })
+}
+
+#[dbus_interface(name = "org.freedesktop.Notifications")]
+impl NotificationServer {
+    #[allow(clippy::too_many_arguments)]
+    async fn notify(
+        &mut self,
+        app_name: String,
+        replaces_id: u32,
+        app_icon: String,
+        summary: String,
+        body: String,
+        actions: Vec<String>,
+        hints: HashMap<String, OwnedValue>,
+        expire_timeout: i32,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<u32> {
+        let id = match self.notifications.remove(&replaces_id) {
+            Some(old) => {
+                if let Some(expiry) = old.expiry {
+                    expiry.abort();
+                }
+                replaces_id
+            }
+            None => {
+                let id = self.next_id;
+                // ids are never 0, that means "new notification" in replaces_id
+                self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+                id
+            }
+        };
+
+        // critical notifications stay until they are dismissed
+        let critical = Urgency::from_hints(&hints) == Urgency::High;
+        let timeout = match expire_timeout {
+            0 => None,
+            t if t < 0 && critical => None,
+            t if t < 0 => Some(DEFAULT_EXPIRE_TIMEOUT),
+            t => Some(Duration::from_millis(t as u64)),
+        };
+
+        debug!("Notification {} from {}: {}", id, app_name, summary);
+        self.notifications.insert(
+            id,
+            Shown {
+                app_name,
+                app_icon,
+                summary,
+                body,
+                actions: actions
+                    .chunks_exact(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect(),
+                hints,
+                expiry: timeout.map(|t| expire(conn.clone(), id, t)),
+            },
+        );
+        self.show(&ctxt, id).await?;
+        Ok(id)
+    }
+
+    async fn close_notification(
+        &mut self,
+        id: u32,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        self.close(&ctxt, id, CloseReason::Closed).await?;
+        Ok(())
+    }
+
+    fn get_capabilities(&self) -> Vec<String> {
+        vec!["actions".to_owned(), "body".to_owned()]
+    }
+
+    fn get_server_information(&self) -> (String, String, String, String) {
+        (
+            "d5".to_owned(),
+            "Fyra Labs".to_owned(),
+            env!("CARGO_PKG_VERSION").to_owned(),
+            "1.2".to_owned(),
+        )
+    }
+
+    #[dbus_interface(signal)]
+    async fn notification_closed(
+        ctxt: &SignalContext<'_>,
+        id: u32,
+        reason: u32,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn action_invoked(
+        ctxt: &SignalContext<'_>,
+        id: u32,
+        action_key: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// `com.fyralabs.d5.Notifications`, how a frontend learns what to draw and reports back
+pub struct NotificationFrontend;
+
+impl NotificationFrontend {
+    async fn server(
+        server: &zbus::ObjectServer,
+    ) -> fdo::Result<zbus::InterfaceRef<NotificationServer>> {
+        Ok(server
+            .interface::<_, NotificationServer>(NOTIFICATIONS_PATH)
+            .await?)
+    }
+}
+
+#[dbus_interface(name = "com.fyralabs.d5.Notifications")]
+impl NotificationFrontend {
+    /// The user clicked an action
+    async fn invoke_action(
+        &self,
+        id: u32,
+        action_key: String,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
+    ) -> fdo::Result<()> {
+        let iface = Self::server(server).await?;
+        let mut notifications = iface.get_mut().await;
+        if !notifications
+            .invoke(iface.signal_context(), id, &action_key)
+            .await?
+        {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "notification {id} has no action `{action_key}`"
+            )));
+        }
+        Ok(())
+    }
+
+    /// The user dismissed the notification
+    async fn dismiss(
+        &self,
+        id: u32,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
+    ) -> fdo::Result<()> {
+        let iface = Self::server(server).await?;
+        let mut notifications = iface.get_mut().await;
+        notifications
+            .close(iface.signal_context(), id, CloseReason::Dismissed)
+            .await?;
+        Ok(())
+    }
+
+    /// Draw a notification, replacing the one with the same id if it is still shown
+    #[allow(clippy::too_many_arguments)]
+    #[dbus_interface(signal)]
+    async fn show(
+        ctxt: &SignalContext<'_>,
+        id: u32,
+        app_name: &str,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: Vec<(String, String)>,
+        urgency: u8,
+    ) -> zbus::Result<()>;
+
+    /// Stop drawing a notification
+    #[dbus_interface(signal)]
+    async fn hide(ctxt: &SignalContext<'_>, id: u32, reason: u32) -> zbus::Result<()>;
+}
+
+/// Serve notifications if nobody else does
+///
+/// Returns `None` if a notification daemon is running or can be activated.
+pub async fn serve(frontend: Option<String>) -> Result<Option<Connection>> {
+    let conn = ConnectionBuilder::session()?
+        .serve_at(NOTIFICATIONS_PATH, NotificationServer::new(frontend))?
+        .serve_at(NOTIFICATIONS_PATH, NotificationFrontend)?
+        .build()
+        .await?;
+
+    let dbus = DBusProxy::new(&conn).await?;
+    let name = NOTIFICATIONS_NAME.try_into()?;
+    if dbus.name_has_owner(NOTIFICATIONS_NAME.try_into()?).await?
+        || dbus
+            .list_activatable_names()
+            .await?
+            .iter()
+            .any(|n| n.as_str() == NOTIFICATIONS_NAME)
+    {
+        info!("A notification daemon is available, not serving notifications");
+        return Ok(None);
+    }
+
+    match dbus
+        .request_name(name, RequestNameFlags::DoNotQueue.into())
+        .await?
+    {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => {
+            info!("Serving {}", NOTIFICATIONS_NAME);
+            Ok(Some(conn))
+        }
+        _ => {
+            info!("Another notification daemon took over, not serving notifications");
+            Ok(None)
+        }
+    }
+}
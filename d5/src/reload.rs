@@ -0,0 +1,334 @@
+//! Config reload
+//!
+//! d5 reloads the session config on SIGHUP, or when one of its files changes. Only services follow
+//! a reload: new ones are started, removed ones stopped and changed ones restarted. The leader and
+//! everything else keep the config the session started with. Reloads run in the background, so a
+//! service slow to become ready does not hold up the rest of the session.
+
+use color_eyre::Result;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use zbus::{Connection, SignalContext};
+use zbus_systemd::systemd1::ManagerProxy as SystemdManagerProxy;
+
+use crate::config::{Config, ConfigSource};
+use crate::service::{ServiceDiff, ServiceSet};
+use crate::session::{D5, D5_PATH};
+
+/// Let a burst of changes, like an editor saving a file, settle before reloading
+const SETTLE: Duration = Duration::from_millis(200);
+
+/// Reloads the config whenever it changes, until stopped
+pub struct Reloader {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Reloader {
+    /// Start following the config, `current` being the config the services were started from
+    pub fn spawn(
+        conn: Connection,
+        systemd: SystemdManagerProxy<'static>,
+        source: ConfigSource,
+        mut current: Config,
+        services: ServiceSet,
+    ) -> Result<Self> {
+        let mut watcher = ConfigWatcher::new(&source)?;
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut stopped => return,
+                    _ = watcher.changed() => {}
+                }
+                reload(&conn, &systemd, &source, &mut current, &services).await;
+            }
+        });
+        Ok(Self { stop, task })
+    }
+
+    /// Stop reloading
+    ///
+    /// A reload already going is finished first, so no service is left half started.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        if let Err(e) = self.task.await {
+            warn!("Config reloading failed: {:?}", e);
+        }
+    }
+}
+
+/// Tells when the config should be reloaded
+struct ConfigWatcher {
+    hangup: Signal,
+    /// `None` if the config directories cannot be watched
+    watches: Option<DirWatches>,
+}
+
+impl ConfigWatcher {
+    fn new(source: &ConfigSource) -> Result<Self> {
+        let hangup = signal(SignalKind::hangup())?;
+        let watches = match DirWatches::new(&source.paths()) {
+            Ok(watches) => Some(watches),
+            Err(e) => {
+                warn!("Not watching the config files for changes: {:?}", e);
+                None
+            }
+        };
+        Ok(Self { hangup, watches })
+    }
+
+    /// Wait until the config should be reloaded
+    async fn changed(&mut self) {
+        loop {
+            let result = tokio::select! {
+                _ = self.hangup.recv() => {
+                    info!("Reloading the config on SIGHUP");
+                    return;
+                }
+                result = file_changed(&mut self.watches) => result,
+            };
+            match result {
+                Ok(()) => {
+                    info!("Reloading the changed config");
+                    return;
+                }
+                Err(e) => {
+                    warn!("Stopped watching the config files: {:?}", e);
+                    self.watches = None;
+                }
+            }
+        }
+    }
+}
+
+/// Wait for a config file to change, forever if nothing is watched
+async fn file_changed(watches: &mut Option<DirWatches>) -> io::Result<()> {
+    match watches {
+        Some(watches) => watches.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Inotify watches on the directories holding the config files
+///
+/// Editors replace files as often as they write them, so watching the files themselves would miss
+/// changes. A directory that does not exist yet is watched through its closest existing ancestor
+/// until it appears.
+struct DirWatches {
+    inotify: AsyncFd<Inotify>,
+    dirs: Vec<PathBuf>,
+    /// Names of the config files in `dirs`
+    names: Vec<OsString>,
+    /// The directory behind every watch, ancestors included
+    watched: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl DirWatches {
+    fn new(paths: &[PathBuf]) -> Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        let mut dirs = paths
+            .iter()
+            .filter_map(|path| path.parent().map(Path::to_owned))
+            .collect::<Vec<_>>();
+        dirs.dedup();
+        let mut names = paths
+            .iter()
+            .filter_map(|path| path.file_name().map(|name| name.to_owned()))
+            .collect::<Vec<_>>();
+        names.dedup();
+
+        let mut watches = Self {
+            inotify: AsyncFd::new(inotify)?,
+            dirs,
+            names,
+            watched: HashMap::new(),
+        };
+        watches.watch()?;
+        Ok(watches)
+    }
+
+    /// Watch every config directory, or its closest existing ancestor, returning the config
+    /// directories that were not watched before
+    fn watch(&mut self) -> io::Result<Vec<PathBuf>> {
+        let flags = AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_FROM
+            | AddWatchFlags::IN_MOVED_TO;
+        let mut appeared = vec![];
+        for dir in &self.dirs {
+            let Some(target) = dir.ancestors().find(|ancestor| ancestor.is_dir()) else {
+                continue;
+            };
+            if self.watched.values().any(|watched| watched == target) {
+                continue;
+            }
+            let wd = self.inotify.get_ref().add_watch(target, flags)?;
+            self.watched.insert(wd, target.to_owned());
+            if target == dir {
+                debug!("Watching {} for config changes", dir.display());
+                appeared.push(dir.clone());
+            } else {
+                debug!(
+                    "Watching {} until {} appears",
+                    target.display(),
+                    dir.display()
+                );
+            }
+        }
+        Ok(appeared)
+    }
+
+    /// Wait for a config file to change, and for the changes to settle
+    async fn changed(&mut self) -> io::Result<()> {
+        loop {
+            let mut ready = self.inotify.readable().await?;
+            let Ok(events) = ready.try_io(|fd| fd.get_ref().read_events().map_err(io::Error::from))
+            else {
+                continue;
+            };
+            drop(ready);
+            if self.follow(events?)? {
+                break;
+            }
+        }
+
+        tokio::time::sleep(SETTLE).await;
+        // whatever else happened meanwhile is part of the same change
+        if let Ok(events) = self.inotify.get_ref().read_events() {
+            self.follow(events)?;
+        }
+        Ok(())
+    }
+
+    /// Keep the watches in line with the directories in `events`, returning whether a config file
+    /// changed
+    fn follow(&mut self, events: Vec<InotifyEvent>) -> io::Result<bool> {
+        let mut changed = false;
+        let mut rewatch = false;
+        for event in events {
+            if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                // the directory is gone, wait for it to come back
+                self.watched.remove(&event.wd);
+                rewatch = true;
+                continue;
+            }
+            rewatch |= event.mask.contains(AddWatchFlags::IN_ISDIR);
+            let Some(dir) = self.watched.get(&event.wd) else {
+                continue;
+            };
+            changed |= self.dirs.contains(dir)
+                && matches!(&event.name, Some(name) if self.names.contains(name));
+        }
+        if rewatch {
+            for dir in self.watch()? {
+                // the files may have been written before the watch was in place
+                changed |= self.names.iter().any(|name| dir.join(name).exists());
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// Load the config again and apply the difference in services, reporting it on D-Bus
+///
+/// `current` becomes the reloaded config once the services follow it.
+async fn reload(
+    conn: &Connection,
+    systemd: &SystemdManagerProxy<'static>,
+    source: &ConfigSource,
+    current: &mut Config,
+    services: &ServiceSet,
+) {
+    let result = apply(conn, systemd, source, current, services).await;
+    let reported = async {
+        let ctxt = SignalContext::new(conn, D5_PATH)?;
+        match &result {
+            Ok(diff) => D5::config_reloaded(&ctxt, &diff.added, &diff.removed, &diff.changed).await,
+            Err(e) => D5::config_reload_failed(&ctxt, &e.to_string()).await,
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to reload the config: {:?}", e);
+    }
+    if let Err(e) = reported {
+        warn!("Failed to report the config reload: {:?}", e);
+    }
+}
+
+async fn apply(
+    conn: &Connection,
+    systemd: &SystemdManagerProxy<'static>,
+    source: &ConfigSource,
+    current: &mut Config,
+    services: &ServiceSet,
+) -> Result<ServiceDiff> {
+    let config = crate::config::load_config(source)?;
+    if outside_services_changed(current, &config)? {
+        info!("Only services are reloaded, other config changes apply to the next session");
+    }
+
+    // a config that cannot be started is turned down before any service is touched
+    let order = config.start_order()?;
+    let diff = ServiceDiff::between(current, &config);
+    if !diff.is_empty() {
+        services.reload(systemd, &config, &order, &diff).await;
+        // the services run as configured now, whether or not their objects follow
+        if let Err(e) =
+            crate::service::update_service_objects(conn, systemd, &config, services, &diff).await
+        {
+            warn!("Failed to update the service objects: {:?}", e);
+        }
+    }
+    info!(
+        added = ?diff.added,
+        removed = ?diff.removed,
+        changed = ?diff.changed,
+        "Reloaded the config"
+    );
+    *current = config;
+    Ok(diff)
+}
+
+fn outside_services_changed(old: &Config, new: &Config) -> Result<bool> {
+    let without_services = |config: &Config| -> Result<toml::Value> {
+        let mut value = toml::Value::try_from(config)?;
+        if let Some(table) = value.as_table_mut() {
+            table.remove("services");
+        }
+        Ok(value)
+    };
+    Ok(without_services(old)? != without_services(new)?)
+}
+
+#[tokio::test]
+async fn config_dirs_are_watched_once_they_appear() {
+    let root = std::env::temp_dir().join(format!("d5-reload-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    let dir = root.join("xdg/d5");
+    let mut watches = DirWatches::new(&[dir.join("kiri.toml")]).unwrap();
+
+    let changed = tokio::spawn(async move { watches.changed().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("kiri.toml"), "").unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), changed)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,144 @@
+//! Transient systemd scopes for launched programs
+//!
+//! Every program d5 launches through the systemd backend is moved into its own
+//! `app-<desktop-id>-<random>.scope` under `session.slice`, so systemd accounts
+//! resources per app and stopping the scope kills the app's whole cgroup.
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use futures::StreamExt;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tracing::{debug, warn};
+use zbus::zvariant::{OwnedValue, Value};
+use zbus_systemd::systemd1::ManagerProxy as SystemdManagerProxy;
+
+/// Slice that app scopes are placed under
+const SESSION_SLICE: &str = "session.slice";
+
+/// Escape a string for use in a systemd unit name, like `systemd-escape`
+pub fn systemd_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for (i, b) in s.bytes().enumerate() {
+        match b {
+            b'/' => out.push('-'),
+            b'.' if i == 0 => out.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => out.push(b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out
+}
+
+/// Name for a new app scope, random so the same app can run more than once
+pub fn scope_name(app_id: &str) -> String {
+    let random = RandomState::new().build_hasher().finish();
+    format!("app-{}-{:08x}.scope", systemd_escape(app_id), random as u32)
+}
+
+/// A program running in its own transient scope
+pub struct AppScope {
+    /// The scope unit, if systemd accepted it
+    pub unit: Option<String>,
+    pub child: Child,
+}
+
+impl AppScope {
+    /// Spawn `cmd` and move it into a new scope for `app_id`
+    pub async fn launch(
+        systemd: &SystemdManagerProxy<'_>,
+        app_id: &str,
+        description: &str,
+        cmd: &[String],
+    ) -> Result<Self> {
+        let (program, args) = cmd
+            .split_first()
+            .ok_or_else(|| eyre!("empty command for {app_id}"))?;
+        let mut child = Command::new(program).args(args).spawn()?;
+        let Some(pid) = child.id() else {
+            // exited before we could even look at it
+            child.wait().await?;
+            bail!("{app_id} exited immediately");
+        };
+
+        let unit = scope_name(app_id);
+        let properties: Vec<(String, OwnedValue)> = vec![
+            ("Description".to_owned(), Value::from(description).into()),
+            ("Slice".to_owned(), Value::from(SESSION_SLICE).into()),
+            ("PIDs".to_owned(), Value::from(vec![pid]).into()),
+            (
+                "CollectMode".to_owned(),
+                Value::from("inactive-or-failed").into(),
+            ),
+        ];
+        let unit = match systemd
+            .start_transient_unit(unit.clone(), "fail".to_string(), properties, vec![])
+            .await
+        {
+            Ok(_) => {
+                debug!(unit = %unit, pid, "Launched app in scope");
+                Some(unit)
+            }
+            Err(e) => {
+                // the app is already running, leave it outside a scope rather than killing it
+                warn!("Failed to create scope {} for pid {}: {:?}", unit, pid, e);
+                None
+            }
+        };
+
+        Ok(Self { unit, child })
+    }
+
+    /// Stop the scope, which kills every process in it, waiting at most `timeout` for it
+    pub async fn stop(
+        mut self,
+        systemd: &SystemdManagerProxy<'_>,
+        timeout: Duration,
+    ) -> Result<()> {
+        match self.unit {
+            Some(unit) => stop_unit(systemd, unit, timeout).await?,
+            None => {
+                if self.child.try_wait()?.is_none() {
+                    if let Some(pid) = self.child.id() {
+                        kill(Pid::from_raw(pid as i32), Signal::SIGTERM)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stop a unit, and wait at most `timeout` for its stop job to finish
+///
+/// StopUnit only queues the job. The caller has to be subscribed to systemd's signals.
+pub async fn stop_unit(
+    systemd: &SystemdManagerProxy<'_>,
+    unit: String,
+    timeout: Duration,
+) -> Result<()> {
+    let mut jobs = systemd.receive_job_removed().await?;
+    let job = systemd
+        .stop_unit(unit.clone(), "replace".to_string())
+        .await?;
+    debug!(unit = %unit, job = %job.as_str(), "Queued stop job");
+    let finished = async {
+        while let Some(removed) = jobs.next().await {
+            let args = removed.args()?;
+            if args.job() == &job {
+                return Ok(args.result().to_owned());
+            }
+        }
+        Err(eyre!("systemd went away while {unit} was stopping"))
+    };
+    match tokio::time::timeout(timeout, finished).await {
+        Ok(Ok(result)) if result == "done" => Ok(()),
+        Ok(Ok(result)) => bail!("stopping {unit} failed: {result}"),
+        Ok(Err(e)) => Err(e),
+        Err(_) => bail!("{unit} did not stop within {}s", timeout.as_secs()),
+    }
+}
@@ -0,0 +1,1245 @@
+//! Session services
+//!
+//! Services are started once the leader is up, and stopped again when the session ends.
+//! Systemd services are started through the user manager, script services are plain child processes
+//! that d5 supervises and restarts according to their restart policy.
+//! Services after a service with `ready` set wait for it to send `READY=1` or to own a bus name.
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use event_listener::Event;
+use futures::future::{join_all, BoxFuture, Shared};
+use futures::{FutureExt, StreamExt};
+use lazy_static::lazy_static;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UnixDatagram;
+use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+use zbus::fdo::DBusProxy;
+use zbus::names::WellKnownName;
+use zbus::{dbus_interface, fdo, Connection};
+use zbus_systemd::systemd1::{
+    ManagerProxy as SystemdManagerProxy, ServiceProxy as SystemdServiceProxy, UnitProxy,
+};
+
+use crate::config::{Config, Readiness, RestartPolicy, ServiceConfig, ServiceType};
+
+/// Delay before the first restart, doubled for every restart within the rate limit window
+const RESTART_BACKOFF: Duration = Duration::from_millis(500);
+/// Upper bound for the restart delay
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// A service may restart at most `RESTART_BURST` times within `RESTART_INTERVAL`
+const RESTART_BURST: usize = 5;
+const RESTART_INTERVAL: Duration = Duration::from_secs(60);
+/// Largest notify message read, longer ones are cut off
+const NOTIFY_MESSAGE_MAX: usize = 4096;
+
+type Registry = Arc<Mutex<ServiceRegistry>>;
+lazy_static! {
+    static ref SERVICE_REGISTRY: Registry = Arc::new(Mutex::new(ServiceRegistry::new()));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceState {
+    Starting,
+    Running,
+    /// Exited, and waiting to be restarted
+    Restarting,
+    /// Exited successfully and will not be restarted
+    Exited,
+    Failed,
+    Stopped,
+}
+
+/// What d5 knows about a service
+#[derive(Debug, Clone)]
+pub struct ServiceStatus {
+    pub service_type: ServiceType,
+    pub state: ServiceState,
+    pub pid: Option<u32>,
+    pub unit: Option<String>,
+    pub restarts: u32,
+    pub last_exit: Option<ExitStatus>,
+    /// The last `STATUS=` a notify service sent
+    pub status_text: Option<String>,
+}
+
+/// Running state of every session service
+pub struct ServiceRegistry {
+    pub services: HashMap<String, ServiceStatus>,
+    /// Notified when a critical service fails for good
+    pub critical_failure: Event,
+    /// Notified whenever the status of a service changes
+    pub changed: Event,
+}
+
+impl ServiceRegistry {
+    fn new() -> Self {
+        Self {
+            services: HashMap::new(),
+            critical_failure: Event::new(),
+            changed: Event::new(),
+        }
+    }
+
+    /// Get the service registry
+    pub fn fetch() -> MutexGuard<'static, ServiceRegistry> {
+        SERVICE_REGISTRY.lock()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ServiceStatus> {
+        self.services.get(name)
+    }
+
+    fn insert(&mut self, name: &str, status: ServiceStatus) {
+        self.services.insert(name.to_owned(), status);
+        self.changed.notify(usize::MAX);
+    }
+
+    fn remove(&mut self, name: &str) {
+        if self.services.remove(name).is_some() {
+            self.changed.notify(usize::MAX);
+        }
+    }
+
+    fn update(&mut self, name: &str, f: impl FnOnce(&mut ServiceStatus)) {
+        if let Some(status) = self.services.get_mut(name) {
+            f(status);
+            self.changed.notify(usize::MAX);
+        }
+    }
+}
+
+enum Running {
+    Script {
+        stop: oneshot::Sender<()>,
+        supervisor: JoinHandle<()>,
+    },
+    Systemd {
+        unit: String,
+        /// Keeps the service's status in line with the unit's
+        follower: JoinHandle<()>,
+        /// How long the unit's stop job is waited for
+        stop_timeout: Duration,
+    },
+}
+
+pub struct RunningService {
+    pub name: String,
+    running: Running,
+}
+
+impl RunningService {
+    /// Start a single service
+    ///
+    /// When stopped, a script service gets `stop_timeout` to exit after SIGTERM before it is killed,
+    /// and a unit's stop job is waited for as long.
+    pub async fn start(
+        systemd: &SystemdManagerProxy<'_>,
+        name: &str,
+        config: &ServiceConfig,
+        stop_timeout: Duration,
+    ) -> Result<Self> {
+        ServiceRegistry::fetch().insert(
+            name,
+            ServiceStatus {
+                service_type: config.service_type,
+                state: ServiceState::Starting,
+                pid: None,
+                unit: config.unit.clone(),
+                restarts: 0,
+                last_exit: None,
+                status_text: None,
+            },
+        );
+
+        let (running, notified) = match config.service_type {
+            ServiceType::Systemd => {
+                let unit = config
+                    .unit
+                    .clone()
+                    .ok_or_else(|| eyre!("service `{name}` has no unit"))?;
+                // StartUnit only queues a job, wait for it to finish so dependents start after it
+                let mut jobs = systemd.receive_job_removed().await?;
+                let job = systemd
+                    .start_unit(unit.clone(), "replace".to_string())
+                    .await?;
+                debug!(service = name, unit = %unit, job = %job.as_str(), "Queued start job");
+                while let Some(removed) = jobs.next().await {
+                    let args = removed.args()?;
+                    if args.job() != &job {
+                        continue;
+                    }
+                    if args.result() != "done" {
+                        bail!("starting {} failed: {}", unit, args.result());
+                    }
+                    break;
+                }
+                let follower = tokio::spawn(follow_unit(
+                    systemd.inner().connection().clone(),
+                    name.to_owned(),
+                    unit.clone(),
+                ));
+                (
+                    Running::Systemd {
+                        unit,
+                        follower,
+                        stop_timeout,
+                    },
+                    None,
+                )
+            }
+            ServiceType::Script => {
+                let notify = match config.ready {
+                    Readiness::Notify => Some(NotifySocket::bind(name)?),
+                    _ => None,
+                };
+                let child = spawn_script(name, config, notify.as_ref())?;
+                let (stop, stopped) = oneshot::channel();
+                let (ready, notified) = oneshot::channel();
+                let supervisor = tokio::spawn(supervise(
+                    name.to_owned(),
+                    config.clone(),
+                    stop_timeout,
+                    child,
+                    notify,
+                    ready,
+                    stopped,
+                ));
+                (Running::Script { stop, supervisor }, Some(notified))
+            }
+        };
+        let service = Self {
+            name: name.to_owned(),
+            running,
+        };
+
+        // dependents only start once the service is ready
+        let ready = async {
+            match (&config.ready, notified) {
+                (Readiness::Notify, Some(notified)) => notified
+                    .await
+                    .map_err(|_| eyre!("service `{name}` exited before it was ready")),
+                (Readiness::DBus(bus_name), _) => {
+                    debug!(service = name, "Waiting for {} on the bus", bus_name);
+                    wait_for_name(systemd.inner().connection(), bus_name).await
+                }
+                _ => Ok(()),
+            }
+        };
+        let ready_timeout = Duration::from_secs(config.ready_timeout);
+        let ready = match tokio::time::timeout(ready_timeout, ready).await {
+            Ok(ready) => ready,
+            Err(_) => Err(eyre!(
+                "service `{name}` was not ready within {}s",
+                config.ready_timeout
+            )),
+        };
+        if let Err(e) = ready {
+            if let Err(e) = service.stop(systemd).await {
+                warn!("Failed to stop service {}: {:?}", name, e);
+            }
+            return Err(e);
+        }
+        // a script that exited already has been marked by its supervisor
+        ServiceRegistry::fetch().update(name, |s| {
+            if s.state == ServiceState::Starting {
+                s.state = ServiceState::Running;
+            }
+        });
+
+        Ok(service)
+    }
+
+    /// Stop the service, killing script services that do not exit in time
+    pub async fn stop(self, systemd: &SystemdManagerProxy<'_>) -> Result<()> {
+        match self.running {
+            Running::Systemd {
+                unit,
+                follower,
+                stop_timeout,
+            } => {
+                // stopping is not the unit failing
+                follower.abort();
+                // dependents stop first, so wait for the unit to be down
+                crate::scope::stop_unit(systemd, unit, stop_timeout).await?;
+                ServiceRegistry::fetch().update(&self.name, |s| s.state = ServiceState::Stopped);
+            }
+            Running::Script { stop, supervisor } => {
+                // the supervisor is gone already if the service exited for good
+                let _ = stop.send(());
+                supervisor.await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keep the status of a systemd service in line with its unit, once the unit is up
+///
+/// systemd supervises the unit, this only tells why it is down.
+async fn follow_unit(conn: Connection, name: String, unit: String) {
+    let result: zbus::Result<()> = async {
+        let path = SystemdManagerProxy::new(&conn)
+            .await?
+            .load_unit(unit.clone())
+            .await?;
+        let unit = UnitProxy::builder(&conn)
+            .path(path.clone())?
+            .build()
+            .await?;
+        let service = SystemdServiceProxy::builder(&conn)
+            .path(path)?
+            .build()
+            .await?;
+        let mut active_state = unit.receive_active_state_changed().await;
+        let mut sub_state = unit.receive_sub_state_changed().await;
+        let mut main_pid = service.receive_exec_main_pid_changed().await;
+        let mut main_status = service.receive_exec_main_status_changed().await;
+        loop {
+            let state = unit_state(&unit.active_state().await?, &unit.sub_state().await?);
+            let pid = service.exec_main_pid().await?;
+            let exit = exec_main_exit(
+                service.exec_main_code().await?,
+                service.exec_main_status().await?,
+            );
+            ServiceRegistry::fetch().update(&name, |s| {
+                s.state = state;
+                s.pid = matches!(state, ServiceState::Running)
+                    .then_some(pid)
+                    .filter(|pid| *pid != 0);
+                if exit.is_some() {
+                    s.last_exit = exit;
+                }
+            });
+            tokio::select! {
+                Some(_) = active_state.next() => {}
+                Some(_) = sub_state.next() => {}
+                Some(_) = main_pid.next() => {}
+                Some(_) = main_status.next() => {}
+                else => return Ok(()),
+            }
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Stopped following the unit of service {}: {:?}", name, e);
+    }
+}
+
+/// What a unit's `ActiveState` and `SubState` mean for the service
+fn unit_state(active_state: &str, sub_state: &str) -> ServiceState {
+    match (active_state, sub_state) {
+        ("activating", "auto-restart") => ServiceState::Restarting,
+        ("activating", _) => ServiceState::Starting,
+        ("active" | "reloading" | "deactivating", _) => ServiceState::Running,
+        ("failed", _) => ServiceState::Failed,
+        ("inactive", _) => ServiceState::Exited,
+        _ => ServiceState::Stopped,
+    }
+}
+
+/// How the unit's main process ended, from `ExecMainCode` and `ExecMainStatus`
+///
+/// The code is a `CLD_*` value, 0 while the process has not exited.
+fn exec_main_exit(code: i32, status: i32) -> Option<ExitStatus> {
+    match code {
+        // CLD_EXITED
+        1 => Some(ExitStatus::from_raw((status & 0xff) << 8)),
+        // CLD_KILLED
+        2 => Some(ExitStatus::from_raw(status)),
+        // CLD_DUMPED
+        3 => Some(ExitStatus::from_raw(status | 0x80)),
+        _ => None,
+    }
+}
+
+/// `NOTIFY_SOCKET` of a script service with `ready = "notify"`, removed again when dropped
+struct NotifySocket {
+    path: PathBuf,
+    socket: UnixDatagram,
+}
+
+impl NotifySocket {
+    fn bind(name: &str) -> Result<Self> {
+        let dir = crate::wayland::runtime_dir()?.join("d5/notify");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!(
+            "{}-{}",
+            std::process::id(),
+            crate::util::bus_path_escape(name)
+        ));
+        // left behind by an earlier d5 that had the same pid
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path)?;
+        Ok(Self { path, socket })
+    }
+
+    async fn recv(&self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; NOTIFY_MESSAGE_MAX];
+        let len = self.socket.recv(&mut buf).await?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+}
+
+impl Drop for NotifySocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Wait for the next notify message, if the service has a socket
+async fn recv_notify(notify: &Option<NotifySocket>) -> io::Result<Vec<u8>> {
+    match notify {
+        Some(notify) => notify.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// `KEY=value` assignments in a notify message, one per line
+fn parse_notify(message: &[u8]) -> Vec<(&str, &str)> {
+    let Ok(message) = std::str::from_utf8(message) else {
+        return vec![];
+    };
+    message
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect()
+}
+
+/// Wait until a bus name has an owner
+pub(crate) async fn wait_for_name(conn: &Connection, name: &str) -> Result<()> {
+    let dbus = DBusProxy::new(conn).await?;
+    // listen before asking, so an owner showing up in between is not missed
+    let mut changes = dbus
+        .receive_name_owner_changed_with_args(&[(0, name)])
+        .await?;
+    if dbus
+        .name_has_owner(WellKnownName::try_from(name)?.into())
+        .await?
+    {
+        return Ok(());
+    }
+    while let Some(change) = changes.next().await {
+        if change.args()?.new_owner().is_some() {
+            return Ok(());
+        }
+    }
+    bail!("the bus went away while waiting for {name}")
+}
+
+fn spawn_script(
+    name: &str,
+    config: &ServiceConfig,
+    notify: Option<&NotifySocket>,
+) -> Result<Child> {
+    let script = config
+        .script
+        .as_deref()
+        .ok_or_else(|| eyre!("service `{name}` has no script"))?;
+    let cmd = shell_words::split(script)?;
+    let (cmd, args) = cmd
+        .split_first()
+        .ok_or_else(|| eyre!("service `{name}` has an empty script"))?;
+    let mut cmd = Command::new(cmd);
+    cmd.args(args);
+    match notify {
+        Some(notify) => cmd.env("NOTIFY_SOCKET", &notify.path),
+        // d5's own socket, if it runs as a notify unit, is not for its services
+        None => cmd.env_remove("NOTIFY_SOCKET"),
+    };
+    let child = cmd.spawn()?;
+    debug!(service = name, pid = ?child.id(), "Spawned script");
+    ServiceRegistry::fetch().update(name, |s| s.pid = child.id());
+    Ok(child)
+}
+
+/// Send SIGTERM to a child, and kill it if it does not exit within `timeout`
+async fn terminate(name: &str, child: &mut Child, timeout: Duration) -> Result<()> {
+    if let Some(pid) = child.id() {
+        kill(Pid::from_raw(pid as i32), Signal::SIGTERM)?;
+    }
+    if tokio::time::timeout(timeout, child.wait()).await.is_err() {
+        warn!(service = name, "Service did not exit in time, killing it");
+        child.kill().await?;
+    }
+    Ok(())
+}
+
+/// Watch a script service, restarting it according to its policy until it is told to stop
+///
+/// `ready` is sent the first time a notify service sends `READY=1`.
+async fn supervise(
+    name: String,
+    config: ServiceConfig,
+    stop_timeout: Duration,
+    mut child: Child,
+    mut notify: Option<NotifySocket>,
+    ready: oneshot::Sender<()>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let mut restarts: VecDeque<Instant> = VecDeque::with_capacity(RESTART_BURST);
+    let mut ready = Some(ready);
+
+    loop {
+        let status = tokio::select! {
+            status = child.wait() => status,
+            message = recv_notify(&notify) => {
+                match message {
+                    Ok(message) => notified(&name, &parse_notify(&message), &mut ready),
+                    Err(e) => {
+                        warn!("Stopped listening on the notify socket of {}: {:?}", name, e);
+                        notify = None;
+                    }
+                }
+                continue;
+            }
+            _ = &mut stop => {
+                if let Err(e) = terminate(&name, &mut child, stop_timeout).await {
+                    warn!("Failed to stop service {}: {:?}", name, e);
+                }
+                ServiceRegistry::fetch().update(&name, |s| {
+                    s.state = ServiceState::Stopped;
+                    s.pid = None;
+                });
+                return;
+            }
+        };
+
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => {
+                error!("Failed to wait for service {}: {:?}", name, e);
+                fail(&name, &config);
+                return;
+            }
+        };
+        let failed = !status.success();
+        info!(service = %name, status = %status, "Service exited");
+
+        let restart = match config.restart {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Always => true,
+        };
+        ServiceRegistry::fetch().update(&name, |s| {
+            s.last_exit = Some(status);
+            s.pid = None;
+            s.status_text = None;
+            s.state = if restart {
+                ServiceState::Restarting
+            } else {
+                ServiceState::Exited
+            };
+        });
+        if !restart {
+            if failed {
+                fail(&name, &config);
+            }
+            return;
+        }
+
+        // rate limit restarts, backing off for every restart within the window
+        let now = Instant::now();
+        while let Some(t) = restarts.front() {
+            if now.duration_since(*t) <= RESTART_INTERVAL {
+                break;
+            }
+            restarts.pop_front();
+        }
+        if restarts.len() >= RESTART_BURST {
+            error!(
+                "Service {} restarted too often ({} times in {:?}), giving up",
+                name, RESTART_BURST, RESTART_INTERVAL
+            );
+            fail(&name, &config);
+            return;
+        }
+        let backoff = RESTART_BACKOFF
+            .saturating_mul(1 << restarts.len())
+            .min(RESTART_BACKOFF_MAX);
+        restarts.push_back(now);
+
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = &mut stop => {
+                ServiceRegistry::fetch().update(&name, |s| s.state = ServiceState::Stopped);
+                return;
+            }
+        }
+
+        child = match spawn_script(&name, &config, notify.as_ref()) {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Failed to restart service {}: {:?}", name, e);
+                fail(&name, &config);
+                return;
+            }
+        };
+        ServiceRegistry::fetch().update(&name, |s| {
+            s.restarts += 1;
+            // a notify service is running again once it says so
+            s.state = if notify.is_some() {
+                ServiceState::Starting
+            } else {
+                ServiceState::Running
+            };
+        });
+    }
+}
+
+/// Apply the assignments a notify service sent
+fn notified(name: &str, assignments: &[(&str, &str)], ready: &mut Option<oneshot::Sender<()>>) {
+    for &(key, value) in assignments {
+        match key {
+            "READY" if value == "1" => {
+                debug!(service = name, "Service is ready");
+                if let Some(ready) = ready.take() {
+                    let _ = ready.send(());
+                }
+                ServiceRegistry::fetch().update(name, |s| s.state = ServiceState::Running);
+            }
+            "STATUS" => {
+                ServiceRegistry::fetch().update(name, |s| s.status_text = Some(value.to_owned()));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Mark a service as failed for good, ending the session if it is critical
+fn fail(name: &str, config: &ServiceConfig) {
+    let mut registry = ServiceRegistry::fetch();
+    registry.update(name, |s| s.state = ServiceState::Failed);
+    if config.critical {
+        error!("Critical service {} failed, ending the session", name);
+        registry.critical_failure.notify(usize::MAX);
+    }
+}
+
+/// Start every configured service in dependency order, logging the ones that fail
+///
+/// Services whose dependencies are independent of each other are started in parallel.
+/// The returned services are in the order they came up.
+pub async fn start_services(
+    systemd: &SystemdManagerProxy<'_>,
+    config: &Config,
+) -> Result<Vec<RunningService>> {
+    // systemd only sends job signals to subscribed clients
+    systemd.subscribe().await?;
+    let stop_timeout = Duration::from_secs(config.session.stop_timeout);
+
+    let started = Mutex::new(Vec::with_capacity(config.services.len()));
+    let mut pending: HashMap<&str, Shared<BoxFuture<'_, bool>>> = HashMap::new();
+
+    for name in config.start_order()? {
+        let service = &config.services[name];
+        let required = join_all(
+            service
+                .requires
+                .iter()
+                .map(|dep| pending[dep.as_str()].clone()),
+        );
+        let ordered = join_all(
+            service
+                .after
+                .iter()
+                .chain(&service.wants)
+                .map(|dep| pending[dep.as_str()].clone()),
+        );
+        let started = &started;
+
+        let fut = async move {
+            let (required, _) = futures::join!(required, ordered);
+            if !required.into_iter().all(|ok| ok) {
+                warn!("Not starting service {}: a required service failed", name);
+                return false;
+            }
+            match RunningService::start(systemd, name, service, stop_timeout).await {
+                Ok(service) => {
+                    info!("Started service {}", name);
+                    started.lock().push(service);
+                    true
+                }
+                Err(e) => {
+                    warn!("Failed to start service {}: {:?}", name, e);
+                    fail(name, service);
+                    false
+                }
+            }
+        };
+        pending.insert(name, fut.boxed().shared());
+    }
+
+    join_all(pending.into_values()).await;
+    Ok(started.into_inner())
+}
+
+/// How the services of a reloaded config differ from the running ones
+#[derive(Debug, Default)]
+pub struct ServiceDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl ServiceDiff {
+    pub fn between(old: &Config, new: &Config) -> Self {
+        let mut diff = Self::default();
+        for (name, service) in &new.services {
+            match old.services.get(name) {
+                None => diff.added.push(name.clone()),
+                Some(old) if old != service => diff.changed.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = old
+            .services
+            .keys()
+            .filter(|name| !new.services.contains_key(*name))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Session services in start order
+///
+/// Shared between the session and the services' D-Bus objects. The lock is never held while a
+/// service starts or stops, so a service slow to become ready holds up nothing but itself.
+#[derive(Clone)]
+pub struct ServiceSet {
+    slots: Arc<Mutex<Vec<(String, Slot)>>>,
+    /// Tells the starts of a service apart, see [`Slot::Starting`]
+    next_start: Arc<AtomicU64>,
+    /// How long script services get to exit after SIGTERM
+    stop_timeout: Duration,
+}
+
+enum Slot {
+    Stopped,
+    /// Being started by whoever holds this number
+    ///
+    /// A start whose number is no longer in the slot was given up on, by a reload or by stopping
+    /// every service, and the service is stopped again once it is up.
+    Starting(u64),
+    Running(RunningService),
+}
+
+impl ServiceSet {
+    pub fn new(config: &Config, started: Vec<RunningService>) -> Result<Self> {
+        let mut started = started
+            .into_iter()
+            .map(|s| (s.name.clone(), s))
+            .collect::<HashMap<_, _>>();
+        let slots = config
+            .start_order()?
+            .into_iter()
+            .map(|name| {
+                let slot = started.remove(name).map_or(Slot::Stopped, Slot::Running);
+                (name.to_owned(), slot)
+            })
+            .collect();
+        Ok(Self {
+            slots: Arc::new(Mutex::new(slots)),
+            next_start: Arc::new(AtomicU64::new(0)),
+            stop_timeout: Duration::from_secs(config.session.stop_timeout),
+        })
+    }
+
+    /// Start a service that is not running
+    pub async fn start(
+        &self,
+        systemd: &SystemdManagerProxy<'_>,
+        name: &str,
+        config: &ServiceConfig,
+    ) -> Result<()> {
+        let start = {
+            let mut slots = self.slots.lock();
+            let Some((_, slot)) = slots.iter_mut().find(|(n, _)| n == name) else {
+                bail!("there is no service `{name}`");
+            };
+            match slot {
+                Slot::Stopped => {}
+                Slot::Starting(_) => bail!("service `{name}` is already starting"),
+                Slot::Running(_) => bail!("service `{name}` is already running"),
+            }
+            let start = self.next_start.fetch_add(1, Ordering::Relaxed);
+            *slot = Slot::Starting(start);
+            start
+        };
+        match RunningService::start(systemd, name, config, self.stop_timeout).await {
+            Ok(service) => {
+                info!("Started service {}", name);
+                self.started(systemd, name, start, Some(service)).await;
+                Ok(())
+            }
+            // the caller gets the error, a failed manual start does not end the session
+            Err(e) => {
+                ServiceRegistry::fetch().update(name, |s| s.state = ServiceState::Failed);
+                self.started(systemd, name, start, None).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Put a service that finished starting in its slot, or stop it if the start was given up on
+    async fn started(
+        &self,
+        systemd: &SystemdManagerProxy<'_>,
+        name: &str,
+        start: u64,
+        service: Option<RunningService>,
+    ) {
+        let given_up = {
+            let mut slots = self.slots.lock();
+            match slots
+                .iter_mut()
+                .find(|(n, slot)| n == name && matches!(slot, Slot::Starting(s) if *s == start))
+            {
+                Some((_, slot)) => {
+                    *slot = service.map_or(Slot::Stopped, Slot::Running);
+                    None
+                }
+                None => service,
+            }
+        };
+        if let Some(service) = given_up {
+            match service.stop(systemd).await {
+                Ok(()) => info!("Stopped service {}, it was no longer wanted", name),
+                Err(e) => warn!("Failed to stop service {}: {:?}", name, e),
+            }
+        }
+    }
+
+    /// Stop a running service
+    pub async fn stop(&self, systemd: &SystemdManagerProxy<'_>, name: &str) -> Result<()> {
+        let service = {
+            let mut slots = self.slots.lock();
+            let Some((_, slot)) = slots.iter_mut().find(|(n, _)| n == name) else {
+                bail!("there is no service `{name}`");
+            };
+            match std::mem::replace(slot, Slot::Stopped) {
+                Slot::Running(service) => service,
+                Slot::Starting(start) => {
+                    *slot = Slot::Starting(start);
+                    bail!("service `{name}` is still starting");
+                }
+                Slot::Stopped => bail!("service `{name}` is not running"),
+            }
+        };
+        service.stop(systemd).await?;
+        info!("Stopped service {}", name);
+        Ok(())
+    }
+
+    /// Names of every service, in start order
+    pub fn names(&self) -> Vec<String> {
+        let slots = self.slots.lock();
+        slots.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Bring the services in line with a reloaded config
+    ///
+    /// Removed services are stopped, changed services that were running are restarted and added
+    /// services are started, following `order`, the reloaded config's start order. Failures are
+    /// reported on the services themselves, so the reloaded config always counts as applied.
+    pub async fn reload(
+        &self,
+        systemd: &SystemdManagerProxy<'_>,
+        config: &Config,
+        order: &[&str],
+        diff: &ServiceDiff,
+    ) {
+        let (stopping, starting) = {
+            let mut slots = self.slots.lock();
+            let mut stopping = vec![];
+            let mut restart = vec![];
+            for (name, slot) in slots.iter_mut().rev() {
+                if !diff.removed.contains(name) && !diff.changed.contains(name) {
+                    continue;
+                }
+                let running = match std::mem::replace(slot, Slot::Stopped) {
+                    Slot::Running(service) => {
+                        stopping.push(service);
+                        true
+                    }
+                    // giving up on the start stops the service once it is up
+                    Slot::Starting(_) => true,
+                    Slot::Stopped => false,
+                };
+                if running && diff.changed.contains(name) {
+                    restart.push(name.clone());
+                }
+            }
+
+            let mut kept = slots.drain(..).collect::<HashMap<_, _>>();
+            *slots = order
+                .iter()
+                .map(|&name| (name.to_owned(), kept.remove(name).unwrap_or(Slot::Stopped)))
+                .collect();
+
+            let mut starting = vec![];
+            for (name, slot) in slots.iter_mut() {
+                if diff.added.contains(name) || restart.contains(name) {
+                    let start = self.next_start.fetch_add(1, Ordering::Relaxed);
+                    *slot = Slot::Starting(start);
+                    starting.push((name.clone(), start));
+                }
+            }
+            (stopping, starting)
+        };
+
+        for service in stopping {
+            let name = service.name.clone();
+            match service.stop(systemd).await {
+                Ok(()) => info!("Stopped service {}", name),
+                Err(e) => warn!("Failed to stop service {}: {:?}", name, e),
+            }
+        }
+        for name in &diff.removed {
+            ServiceRegistry::fetch().remove(name);
+        }
+
+        for (name, start) in starting {
+            let service = &config.services[name.as_str()];
+            let started =
+                match RunningService::start(systemd, &name, service, self.stop_timeout).await {
+                    Ok(started) => {
+                        info!("Started service {}", name);
+                        Some(started)
+                    }
+                    Err(e) => {
+                        warn!("Failed to start service {}: {:?}", name, e);
+                        fail(&name, service);
+                        None
+                    }
+                };
+            self.started(systemd, &name, start, started).await;
+        }
+    }
+
+    /// Stop every running service, in reverse dependency order
+    ///
+    /// Services still starting are stopped once they are up.
+    pub async fn stop_all(&self, systemd: &SystemdManagerProxy<'_>) {
+        let stopping = {
+            let mut slots = self.slots.lock();
+            slots
+                .iter_mut()
+                .rev()
+                .filter_map(|(_, slot)| match std::mem::replace(slot, Slot::Stopped) {
+                    Slot::Running(service) => Some(service),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        for service in stopping {
+            let name = service.name.clone();
+            match service.stop(systemd).await {
+                Ok(()) => info!("Stopped service {}", name),
+                Err(e) => warn!("Failed to stop service {}: {:?}", name, e),
+            }
+        }
+    }
+}
+
+/// D-Bus path of a service's object
+pub fn service_path(name: &str) -> String {
+    format!(
+        "{}/services/{}",
+        crate::session::D5_PATH,
+        crate::util::bus_path_escape(name)
+    )
+}
+
+/// `com.fyralabs.d5.Service`, one object per configured service
+pub struct ServiceObject {
+    name: String,
+    config: ServiceConfig,
+    services: ServiceSet,
+    systemd: SystemdManagerProxy<'static>,
+}
+
+impl ServiceObject {
+    fn status(&self) -> Option<ServiceStatus> {
+        ServiceRegistry::fetch().get(&self.name).cloned()
+    }
+}
+
+#[dbus_interface(name = "com.fyralabs.d5.Service")]
+impl ServiceObject {
+    async fn start(&self) -> fdo::Result<()> {
+        self.services
+            .start(&self.systemd, &self.name, &self.config)
+            .await
+            .map_err(|e| fdo::Error::Failed(format!("{e:?}")))
+    }
+
+    async fn stop(&self) -> fdo::Result<()> {
+        self.services
+            .stop(&self.systemd, &self.name)
+            .await
+            .map_err(|e| fdo::Error::Failed(format!("{e:?}")))
+    }
+
+    /// Stop the service if it is running, then start it again
+    async fn restart(&self) -> fdo::Result<()> {
+        if let Err(e) = self.services.stop(&self.systemd, &self.name).await {
+            debug!(
+                "Not stopping service {} before restarting it: {:?}",
+                self.name, e
+            );
+        }
+        self.start().await
+    }
+
+    #[dbus_interface(property)]
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// `script` or `systemd`
+    #[dbus_interface(property, name = "Type")]
+    fn service_type(&self) -> String {
+        match self.config.service_type {
+            ServiceType::Script => "script",
+            ServiceType::Systemd => "systemd",
+        }
+        .to_owned()
+    }
+
+    /// `starting`, `running`, `restarting`, `exited`, `failed` or `stopped`
+    #[dbus_interface(property)]
+    fn state(&self) -> String {
+        let state = self
+            .status()
+            .map(|s| s.state)
+            .unwrap_or(ServiceState::Stopped);
+        match state {
+            ServiceState::Starting => "starting",
+            ServiceState::Running => "running",
+            ServiceState::Restarting => "restarting",
+            ServiceState::Exited => "exited",
+            ServiceState::Failed => "failed",
+            ServiceState::Stopped => "stopped",
+        }
+        .to_owned()
+    }
+
+    /// Process ID of the script, or of the unit's main process, 0 if there is none
+    #[dbus_interface(property)]
+    fn pid(&self) -> u32 {
+        self.status().and_then(|s| s.pid).unwrap_or(0)
+    }
+
+    #[dbus_interface(property)]
+    fn unit(&self) -> String {
+        self.config.unit.clone().unwrap_or_default()
+    }
+
+    #[dbus_interface(property)]
+    fn restarts(&self) -> u32 {
+        self.status().map(|s| s.restarts).unwrap_or(0)
+    }
+
+    /// The last `STATUS=` text of a notify service, empty if there is none
+    #[dbus_interface(property)]
+    fn status_text(&self) -> String {
+        self.status()
+            .and_then(|s| s.status_text)
+            .unwrap_or_default()
+    }
+
+    /// Exit code of the last run, 128 + the signal if it was killed, -1 if it never exited
+    #[dbus_interface(property)]
+    fn last_exit_code(&self) -> i32 {
+        match self.status().and_then(|s| s.last_exit) {
+            Some(status) => status
+                .code()
+                .or_else(|| status.signal().map(|signal| 128 + signal))
+                .unwrap_or(-1),
+            None => -1,
+        }
+    }
+}
+
+async fn serve_service(
+    conn: &Connection,
+    systemd: &SystemdManagerProxy<'static>,
+    name: &str,
+    config: &ServiceConfig,
+    services: &ServiceSet,
+) -> zbus::Result<()> {
+    conn.object_server()
+        .at(
+            service_path(name),
+            ServiceObject {
+                name: name.to_owned(),
+                config: config.clone(),
+                services: services.clone(),
+                systemd: systemd.clone(),
+            },
+        )
+        .await?;
+    Ok(())
+}
+
+/// Serve an object for every configured service, and keep their properties up to date
+pub async fn serve_services(
+    conn: &Connection,
+    systemd: &SystemdManagerProxy<'static>,
+    config: &Config,
+    services: &ServiceSet,
+) -> Result<()> {
+    for (name, service) in &config.services {
+        serve_service(conn, systemd, name, service, services).await?;
+    }
+
+    let conn = conn.clone();
+    let services = services.clone();
+    tokio::spawn(async move {
+        let result: zbus::Result<()> = async {
+            loop {
+                let changed = ServiceRegistry::fetch().changed.listen();
+                changed.await;
+                for name in services.names() {
+                    // the object may be on its way out after a reload
+                    let Ok(iface) = conn
+                        .object_server()
+                        .interface::<_, ServiceObject>(service_path(&name))
+                        .await
+                    else {
+                        continue;
+                    };
+                    let object = iface.get().await;
+                    let ctxt = iface.signal_context();
+                    object.state_changed(ctxt).await?;
+                    object.pid_changed(ctxt).await?;
+                    object.restarts_changed(ctxt).await?;
+                    object.last_exit_code_changed(ctxt).await?;
+                    object.status_text_changed(ctxt).await?;
+                }
+            }
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Stopped announcing service changes: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Replace the objects of services that a reload added, removed or changed
+pub async fn update_service_objects(
+    conn: &Connection,
+    systemd: &SystemdManagerProxy<'static>,
+    config: &Config,
+    services: &ServiceSet,
+    diff: &ServiceDiff,
+) -> zbus::Result<()> {
+    for name in diff.removed.iter().chain(&diff.changed) {
+        conn.object_server()
+            .remove::<ServiceObject, _>(service_path(name))
+            .await?;
+    }
+    for name in diff.added.iter().chain(&diff.changed) {
+        serve_service(conn, systemd, name, &config.services[name], services).await?;
+    }
+    Ok(())
+}
+
+#[test]
+fn reload_diff_covers_every_service() {
+    let old: Config = toml::from_str(
+        r#"
+        [session]
+        leader = "kiri"
+
+        [services.kept]
+        type = "script"
+        script = "kept"
+
+        [services.gone]
+        type = "script"
+        script = "gone"
+
+        [services.edited]
+        type = "script"
+        script = "edited"
+        "#,
+    )
+    .unwrap();
+    let new: Config = toml::from_str(
+        r#"
+        [session]
+        leader = "something else"
+
+        [services.kept]
+        type = "script"
+        script = "kept"
+
+        [services.edited]
+        type = "script"
+        script = "edited --verbose"
+
+        [services.new]
+        type = "script"
+        script = "new"
+        "#,
+    )
+    .unwrap();
+
+    let diff = ServiceDiff::between(&old, &new);
+    assert_eq!(diff.added, ["new"]);
+    assert_eq!(diff.removed, ["gone"]);
+    assert_eq!(diff.changed, ["edited"]);
+    assert!(ServiceDiff::between(&new, &new).is_empty());
+}
+
+#[test]
+fn notify_messages_are_parsed() {
+    assert_eq!(
+        parse_notify(b"READY=1\nSTATUS=Indexing 3 of 10 folders\nMAINPID=4242"),
+        [
+            ("READY", "1"),
+            ("STATUS", "Indexing 3 of 10 folders"),
+            ("MAINPID", "4242"),
+        ]
+    );
+    assert_eq!(parse_notify(b"STATUS=a=b\n\nWATCHDOG"), [("STATUS", "a=b")]);
+    assert!(parse_notify(b"STATUS=\xff").is_empty());
+}
+
+#[test]
+fn unit_status_is_translated() {
+    assert_eq!(unit_state("active", "running"), ServiceState::Running);
+    assert_eq!(
+        unit_state("activating", "auto-restart"),
+        ServiceState::Restarting
+    );
+    assert_eq!(unit_state("failed", "failed"), ServiceState::Failed);
+    assert_eq!(unit_state("inactive", "dead"), ServiceState::Exited);
+
+    assert_eq!(exec_main_exit(0, 0), None);
+    assert_eq!(exec_main_exit(1, 3).unwrap().code(), Some(3));
+    assert_eq!(exec_main_exit(2, 9).unwrap().signal(), Some(9));
+    assert_eq!(exec_main_exit(3, 11).unwrap().signal(), Some(11));
+}
@@ -2,33 +2,372 @@
 
 use color_eyre::Result;
 use event_listener::Event;
-use futures::{pending, StreamExt};
-use logind_zbus::manager::{self, ManagerProxy};
+use logind_zbus::manager::{IsSupported, ManagerProxy};
 use logind_zbus::session::SessionProxy;
-use tracing::{debug, info};
-use zbus::dbus_interface;
-use zbus::fdo::DBusProxy;
-use zbus::ObjectServer;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{dbus_interface, fdo, Connection, MessageHeader, SignalContext};
 use zbus_systemd::systemd1::ManagerProxy as SystemdManagerProxy;
 
-use crate::config::Config;
+use crate::cli::DisplayMode;
+use crate::config::{Config, ConfigSource};
+use crate::idle::IdleActions;
+use crate::inhibit::{InhibitorRegistry, INHIBIT_SUSPEND, INHIBIT_SWITCH_USER};
+use crate::leader::LeaderSupervisor;
+use crate::locker::Locker;
+use crate::notify::history::History;
+use crate::util::message_sender;
+
+pub const D5_NAME: &str = "com.fyralabs.d5";
+pub const D5_PATH: &str = "/com/fyralabs/d5";
+
+/// Where the session is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// The leader, services and autostart apps are being started
+    Starting,
+    Running,
+    /// Asking clients and inhibitors whether the session may end
+    QueryEnding,
+    /// Everything is being stopped
+    Ending,
+}
+
+impl SessionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionState::Starting => "Starting",
+            SessionState::Running => "Running",
+            SessionState::QueryEnding => "QueryEnding",
+            SessionState::Ending => "Ending",
+        }
+    }
+}
+
+/// What to do once the session is over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndAction {
+    Logout,
+    PowerOff,
+    Reboot,
+}
+
+/// Request to end the session, shared by every D-Bus interface that can end it
+#[derive(Clone)]
+pub struct SessionEnd {
+    event: Arc<Event>,
+    action: Arc<Mutex<(EndAction, bool)>>,
+}
+
+impl SessionEnd {
+    pub fn new() -> Self {
+        Self {
+            event: Arc::new(Event::new()),
+            action: Arc::new(Mutex::new((EndAction::Logout, false))),
+        }
+    }
+
+    /// Ask for the session to end, `force` skips asking clients and inhibitors
+    pub fn request(&self, action: EndAction, force: bool) {
+        *self.action.lock() = (action, force);
+        self.event.notify(usize::MAX);
+    }
+
+    pub fn listen(&self) -> event_listener::EventListener {
+        self.event.listen()
+    }
+
+    pub fn action(&self) -> EndAction {
+        self.action.lock().0
+    }
+
+    pub fn forced(&self) -> bool {
+        self.action.lock().1
+    }
+}
+
+/// logind's answer to CanPowerOff and friends
+fn supported(answer: IsSupported) -> String {
+    match answer {
+        IsSupported::Yes => "yes",
+        IsSupported::No => "no",
+        IsSupported::Challenge => "challenge",
+        IsSupported::NA => "na",
+    }
+    .to_owned()
+}
+
+/// Ask for the session to end, unless logind would refuse what comes after it
+///
+/// Powering off and rebooting happen once every app is gone, with nobody left to authenticate,
+/// so anything short of a plain "yes" is refused before the logout starts.
+pub async fn request_end(
+    end: &SessionEnd,
+    logind: &ManagerProxy<'_>,
+    action: EndAction,
+) -> fdo::Result<()> {
+    if !can_end(logind, action).await? {
+        return Err(fdo::Error::AccessDenied(format!(
+            "{action:?} is not allowed without authentication"
+        )));
+    }
+    end.request(action, false);
+    Ok(())
+}
+
+/// Whether logind lets the user carry out `action` without asking
+pub async fn can_end(logind: &ManagerProxy<'_>, action: EndAction) -> zbus::Result<bool> {
+    let answer = match action {
+        EndAction::Logout => return Ok(true),
+        EndAction::PowerOff => logind.can_power_off().await?,
+        EndAction::Reboot => logind.can_reboot().await?,
+    };
+    Ok(answer == IsSupported::Yes)
+}
+
+/// Switch to the display manager's greeter, keeping this session running
+async fn switch_to_greeter() -> zbus::Result<()> {
+    let sys = zbus::Connection::system().await?;
+    let gdm = sys
+        .call_method(
+            Some("org.gnome.DisplayManager"),
+            "/org/gnome/DisplayManager/LocalDisplayFactory",
+            Some("org.gnome.DisplayManager.LocalDisplayFactory"),
+            "CreateTransientDisplay",
+            &(),
+        )
+        .await;
+    let Err(e) = gdm else {
+        return Ok(());
+    };
+    debug!("GDM could not switch users: {:?}", e);
+
+    // LightDM exports the seat it runs on
+    let seat = std::env::var("XDG_SEAT_PATH")
+        .map_err(|_| zbus::Error::Failure("no display manager to switch users with".to_owned()))?;
+    sys.call_method(
+        Some("org.freedesktop.DisplayManager"),
+        seat.as_str(),
+        Some("org.freedesktop.DisplayManager.Seat"),
+        "SwitchToGreeter",
+        &(),
+    )
+    .await?;
+    Ok(())
+}
 
 // catch the signal when ending session
-struct D5 {
-    pub quit_event: Event,
+pub struct D5 {
+    pub end: SessionEnd,
+    logind: ManagerProxy<'static>,
+    state: SessionState,
+    /// Set once the session services are up
+    services: Option<crate::service::ServiceSet>,
+}
+
+/// Update the `SessionState` property of d5's object on `conn`
+pub async fn set_session_state(conn: &zbus::Connection, state: SessionState) {
+    let result = async {
+        let iface = conn.object_server().interface::<_, D5>(D5_PATH).await?;
+        let mut d5 = iface.get_mut().await;
+        d5.state = state;
+        d5.session_state_changed(iface.signal_context()).await
+    }
+    .await;
+    match result {
+        Ok(()) => debug!("Session state: {}", state.as_str()),
+        Err(e) => warn!("Failed to update the session state: {:?}", e),
+    }
 }
 
 #[dbus_interface(name = "com.fyralabs.d5")]
 impl D5 {
     fn goodbye_declaration(&self) {
         info!("Stopping session");
-        self.quit_event.notify(1);
+        self.end.request(EndAction::Logout, false);
+    }
+
+    fn logout(&self) {
+        info!("Logout requested");
+        self.end.request(EndAction::Logout, false);
+    }
+
+    /// Log out, then power off
+    async fn power_off(&self) -> fdo::Result<()> {
+        info!("Power off requested");
+        request_end(&self.end, &self.logind, EndAction::PowerOff).await
+    }
+
+    /// Log out, then reboot
+    async fn reboot(&self) -> fdo::Result<()> {
+        info!("Reboot requested");
+        request_end(&self.end, &self.logind, EndAction::Reboot).await
+    }
+
+    async fn suspend(&self) -> fdo::Result<()> {
+        // logind lets the user's own block locks through, so they are checked here
+        if InhibitorRegistry::fetch().is_inhibited(INHIBIT_SUSPEND) {
+            return Err(fdo::Error::AccessDenied("suspend is inhibited".to_owned()));
+        }
+        info!("Suspend requested");
+        self.logind.suspend(false).await?;
+        Ok(())
+    }
+
+    async fn hibernate(&self) -> fdo::Result<()> {
+        if InhibitorRegistry::fetch().is_inhibited(INHIBIT_SUSPEND) {
+            return Err(fdo::Error::AccessDenied(
+                "hibernation is inhibited".to_owned(),
+            ));
+        }
+        info!("Hibernate requested");
+        self.logind.hibernate(false).await?;
+        Ok(())
+    }
+
+    async fn switch_user(&self) -> fdo::Result<()> {
+        if InhibitorRegistry::fetch().is_inhibited(INHIBIT_SWITCH_USER) {
+            return Err(fdo::Error::AccessDenied(
+                "switching users is inhibited".to_owned(),
+            ));
+        }
+        info!("Switching users");
+        switch_to_greeter().await?;
+        Ok(())
     }
+
+    /// `Starting`, `Running`, `QueryEnding` or `Ending`
+    #[dbus_interface(property)]
+    fn session_state(&self) -> String {
+        self.state.as_str().to_owned()
+    }
+
+    #[dbus_interface(property)]
+    async fn can_power_off(&self) -> fdo::Result<String> {
+        Ok(supported(self.logind.can_power_off().await?))
+    }
+
+    #[dbus_interface(property)]
+    async fn can_reboot(&self) -> fdo::Result<String> {
+        Ok(supported(self.logind.can_reboot().await?))
+    }
+
+    #[dbus_interface(property)]
+    async fn can_suspend(&self) -> fdo::Result<String> {
+        Ok(supported(self.logind.can_suspend().await?))
+    }
+
+    #[dbus_interface(property)]
+    async fn can_hibernate(&self) -> fdo::Result<String> {
+        Ok(supported(self.logind.can_hibernate().await?))
+    }
+
+    /// Inhibit logout (1), switching users (2), suspend (4) or idle (8) until the caller
+    /// uninhibits or leaves the bus
+    async fn inhibit(
+        &self,
+        app_id: String,
+        reason: String,
+        flags: u32,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] conn: &Connection,
+    ) -> fdo::Result<u32> {
+        let sender = message_sender(&header)?;
+        crate::inhibit::inhibit(conn, &app_id, &reason, flags, &sender).await
+    }
+
+    fn uninhibit(&self, cookie: u32, #[zbus(header)] header: MessageHeader<'_>) -> fdo::Result<()> {
+        crate::inhibit::uninhibit(cookie, &message_sender(&header)?)
+    }
+
+    /// The environment services and autostart apps are started with
+    fn get_environment(&self) -> HashMap<String, String> {
+        std::env::vars().collect()
+    }
+
+    /// Object paths of the configured services, in start order
+    fn list_services(&self) -> fdo::Result<Vec<OwnedObjectPath>> {
+        let Some(services) = &self.services else {
+            return Ok(vec![]);
+        };
+        services
+            .names()
+            .iter()
+            .map(|name| OwnedObjectPath::try_from(crate::service::service_path(name)))
+            .collect::<Result<_, _>>()
+            .map_err(|e| fdo::Error::Failed(format!("{e:?}")))
+    }
+
+    /// Cookie, app ID, reason, flags and bus name of every inhibitor
+    fn list_inhibitors(&self) -> Vec<(u32, String, String, u32, String)> {
+        InhibitorRegistry::fetch()
+            .inhibitors
+            .iter()
+            .map(|(cookie, i)| {
+                (
+                    *cookie,
+                    i.app_id.clone(),
+                    i.reason.clone(),
+                    i.flags,
+                    i.sender.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// Entered a phase of the logout sequence, see `LogoutPhase`
+    #[dbus_interface(signal)]
+    pub async fn logout_phase(ctxt: &SignalContext<'_>, phase: &str) -> zbus::Result<()>;
+
+    /// The logout was called off, these apps did not let it through
+    #[dbus_interface(signal)]
+    pub async fn logout_cancelled(
+        ctxt: &SignalContext<'_>,
+        blockers: Vec<(String, String)>,
+    ) -> zbus::Result<()>;
+
+    /// The session config was reloaded, and these services were added, removed and restarted
+    #[dbus_interface(signal)]
+    pub async fn config_reloaded(
+        ctxt: &SignalContext<'_>,
+        added: &[String],
+        removed: &[String],
+        changed: &[String],
+    ) -> zbus::Result<()>;
+
+    /// The session config could not be reloaded, the running services were left alone
+    #[dbus_interface(signal)]
+    pub async fn config_reload_failed(ctxt: &SignalContext<'_>, error: &str) -> zbus::Result<()>;
+}
+
+/// The logind session d5 runs in, on its own object path
+///
+/// logind only signals `Lock` and `Unlock`, and announces property changes, on that path, never
+/// on `session/auto`.
+pub async fn own_session(
+    sys: &Connection,
+    manager: &ManagerProxy<'_>,
+) -> zbus::Result<SessionProxy<'static>> {
+    let auto = SessionProxy::builder(sys)
+        .path("/org/freedesktop/login1/session/auto")?
+        .build()
+        .await?;
+    let id = auto.id().await?;
+    debug!("Session ID: {:?}", id);
+    let path = manager.get_session(&id).await?;
+    debug!("Session: {:?}", path);
+    SessionProxy::builder(sys).path(path)?.build().await
 }
 
 // session management
-pub async fn new_session(config: Config) -> Result<()> {
+pub async fn new_session(config: Config, source: ConfigSource, display: DisplayMode) -> Result<()> {
     let conn = zbus::Connection::session().await?;
+    crate::env::set_display_env(display);
+    crate::env::set_session_env(&config.environment)?;
 
     // load the systemd target for the session
 
@@ -46,14 +385,8 @@ pub async fn new_session(config: Config) -> Result<()> {
     //     }
     // });
 
-    // shell_words to split command
-    let cmd = shell_words::split(&config.session.leader).unwrap();
-    let (cmd, args) = cmd.split_first().unwrap();
-
-    let mut cmd = tokio::process::Command::new(cmd)
-        .args(args)
-        .spawn()
-        .expect("Failed to spawn command");
+    let mut supervisor = LeaderSupervisor::new(&config.session, display);
+    let mut leader = supervisor.start().await?;
 
     // activate session
     // manager.activate_session(&session_id).await?;
@@ -61,40 +394,208 @@ pub async fn new_session(config: Config) -> Result<()> {
     let manager = ManagerProxy::new(&sys).await?;
     // recieve exit code from target
 
-    let sess = SessionProxy::builder(&sys)
-        .path("/org/freedesktop/login1/session/auto")?
-        .build()
-        .await?;
-    let id = sess.id().await?;
-    debug!("Session ID: {:?}", id);
+    let sess = own_session(&sys, &manager).await?;
+    sess.activate().await?;
 
-    let session = manager.get_session(&id).await?;
+    // inhibitors hold logind locks on behalf of their apps
+    InhibitorRegistry::fetch().set_logind(manager.clone());
 
-    sess.activate().await?;
-    debug!("Session: {:?}", session);
+    // `loginctl lock-session` and suspend lock the screen
+    if let Some(command) = &config.session.locker {
+        let locker = Locker::new(
+            command.clone(),
+            config.session.lock_before_sleep,
+            sess.clone(),
+            manager.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = locker.watch().await {
+                warn!("Stopped following lock requests: {:?}", e);
+            }
+        });
+    }
 
-    let event = Event::new();
-    let listener = event.listen();
-    let session = D5 { quit_event: event };
+    // dim, lock and suspend as the session stays idle
+    if !config.idle.actions.is_empty() {
+        let idle = IdleActions::new(&config.idle, sess.clone(), manager.clone());
+        tokio::spawn(async move {
+            if let Err(e) = idle.watch().await {
+                warn!("Stopped following the idle hint: {:?}", e);
+            }
+        });
+    }
 
-    let handle = crate::proc::BusHandle::from_interface(
-        session,
-        "com.fyralabs.d5".to_owned(),
-        "/com/fyralabs/d5".to_owned(),
-    )
-    .await?;
+    let end = SessionEnd::new();
+    let session = D5 {
+        end: end.clone(),
+        logind: manager.clone(),
+        state: SessionState::Starting,
+        services: None,
+    };
+
+    let handle =
+        crate::proc::BusHandle::from_interface(session, D5_NAME.to_owned(), D5_PATH.to_owned())
+            .await?;
+    let d5_conn = handle.get_conn().clone();
     // object server
     crate::proc::HandleManager::fetch().add_handle(handle);
 
-    // tokio select wait for listener signal or wait for cmd to finish
-    tokio::select! {
-        _ = cmd.wait() => {
-            info!("Command finished");
+    if config.notifications.server {
+        match crate::notify::server::serve(config.notifications.frontend.clone()).await {
+            Ok(Some(notifications)) => {
+                crate::proc::HandleManager::fetch().add_handle(crate::proc::BusHandle::new(
+                    notifications,
+                    crate::notify::server::NOTIFICATIONS_NAME.to_owned(),
+                    crate::notify::server::NOTIFICATIONS_PATH.to_owned(),
+                ))
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to serve notifications: {:?}", e),
         }
-        _ = listener => {
-            info!("Listener finished");
+    }
+
+    // keep every notification around for a "what did I miss" view, whoever showed it
+    if config.notifications.history.enabled {
+        let history = History::default_dir()
+            .and_then(|dir| History::open(&dir, config.notifications.history.clone()));
+        match history {
+            Ok(history) => {
+                let history = Arc::new(Mutex::new(history));
+                crate::notify::history::keep_saved(history.clone());
+                crate::notify::history::serve(&d5_conn, history.clone()).await?;
+                tokio::spawn(async move {
+                    if let Err(e) = crate::notify::listen(history).await {
+                        warn!("Stopped recording notifications: {:?}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to open the notification history: {:?}", e),
         }
     }
-    // listener.await;
+
+    // GNOME apps register with, and take inhibitors from, org.gnome.SessionManager
+    let gnome = crate::proc::BusHandle::from_interface(
+        crate::gnome::SessionManager::new(end.clone(), manager.clone()),
+        crate::gnome::GNOME_SM_NAME.to_owned(),
+        crate::gnome::GNOME_SM_PATH.to_owned(),
+    )
+    .await?;
+    let gnome_conn = gnome.get_conn().clone();
+    crate::gnome::watch_disconnects(gnome_conn.clone());
+    crate::gnome::watch_inhibitors(gnome_conn.clone());
+    crate::inhibit::watch_disconnects(gnome_conn.clone());
+    crate::proc::HandleManager::fetch().add_handle(gnome);
+
+    // let D-Bus and systemd activated apps find the leader's display
+    let systemd = SystemdManagerProxy::new(&conn).await?;
+    // the user manager outlives the session, logging out puts its environment back
+    let previous_env = crate::env::PreviousEnv::save(&systemd, &config.export_env()).await?;
+    crate::env::export_session_env(&conn, &systemd, &config.export_env()).await?;
+
+    // start the session services now that the leader is up
+    let mut critical_failure = crate::service::ServiceRegistry::fetch()
+        .critical_failure
+        .listen();
+    let services = crate::service::start_services(&systemd, &config).await?;
+    let services = crate::service::ServiceSet::new(&config, services)?;
+    crate::service::serve_services(&d5_conn, &systemd, &config, &services).await?;
+    d5_conn
+        .object_server()
+        .interface::<_, D5>(D5_PATH)
+        .await?
+        .get_mut()
+        .await
+        .services = Some(services.clone());
+    // services follow config changes, the leader keeps `config`
+    let reloader = crate::reload::Reloader::spawn(
+        d5_conn.clone(),
+        systemd.clone(),
+        source,
+        config.clone(),
+        services.clone(),
+    )?;
+
+    let autostart = if config.session.autostart {
+        crate::autostart::start_autostart(&systemd, config.session.xdg_autostart).await
+    } else {
+        vec![]
+    };
+    set_session_state(&d5_conn, SessionState::Running).await;
+
+    let logout = crate::logout::Logout {
+        conn: &d5_conn,
+        gnome: &gnome_conn,
+        query_timeout: Duration::from_secs(config.session.logout_timeout),
+        stop_timeout: Duration::from_secs(config.session.stop_timeout),
+    };
+
+    // run until the leader exits, a critical service fails or a logout goes through
+    let mut end_requested = end.listen();
+    let (leader_running, action) = loop {
+        tokio::select! {
+            status = leader.child.wait() => {
+                let status = status?;
+                leader.log_exit(&status);
+                if status.success() {
+                    break (false, EndAction::Logout);
+                }
+                match supervisor.recover(&leader).await {
+                    Ok(replacement) => {
+                        leader = replacement;
+                        // a new compositor may listen on another socket
+                        if let Err(e) = crate::env::export_session_env(
+                            &conn,
+                            &systemd,
+                            &config.export_env(),
+                        )
+                        .await
+                        {
+                            warn!("Failed to export the session environment: {:?}", e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Ending the session: {:?}", e);
+                        break (false, EndAction::Logout);
+                    }
+                }
+            }
+            _ = &mut critical_failure => {
+                info!("Critical service failed");
+                break (true, EndAction::Logout);
+            }
+            _ = &mut end_requested => {
+                end_requested = end.listen();
+                if end.forced() {
+                    info!("Forced logout");
+                    break (true, end.action());
+                }
+                let blockers = logout.query().await;
+                if blockers.is_empty() {
+                    break (true, end.action());
+                }
+                logout.cancel(blockers).await;
+            }
+        }
+    };
+
+    reloader.stop().await;
+    logout
+        .run(
+            &systemd,
+            autostart,
+            &services,
+            leader_running.then_some(&mut leader.child),
+        )
+        .await;
+
+    if let Err(e) = crate::env::unexport_session_env(&conn, &systemd, &previous_env).await {
+        debug!("Failed to unset session environment: {:?}", e);
+    }
+
+    match action {
+        EndAction::Logout => {}
+        EndAction::PowerOff => manager.power_off(false).await?,
+        EndAction::Reboot => manager.reboot(false).await?,
+    }
     Ok(())
 }
@@ -0,0 +1,169 @@
+//! Test helpers: a D-Bus of our own, and a stand-in for logind to put on it
+
+use parking_lot::Mutex;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{dbus_interface, fdo, Connection, ConnectionBuilder, SignalContext};
+
+lazy_static::lazy_static! {
+    /// Inhibitors are global and unique names repeat between buses, so one bus at a time
+    static ref PRIVATE_BUS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A dbus-daemon of our own, so tests do not touch the real session bus
+pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+    _lock: parking_lot::MutexGuard<'static, ()>,
+}
+
+impl PrivateBus {
+    pub fn start() -> Option<Self> {
+        let lock = PRIVATE_BUS_LOCK.lock();
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.as_mut()?)
+            .read_line(&mut address)
+            .ok()?;
+        Some(Self {
+            daemon,
+            address: address.trim().to_owned(),
+            _lock: lock,
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub async fn connect(&self) -> Connection {
+        ConnectionBuilder::address(self.address())
+            .unwrap()
+            .build()
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// Start a private bus, or skip the test if there is no dbus-daemon
+macro_rules! private_bus {
+    () => {
+        match $crate::testbus::PrivateBus::start() {
+            Some(bus) => bus,
+            None => {
+                eprintln!("dbus-daemon is not available, skipping");
+                return;
+            }
+        }
+    };
+}
+
+pub const SESSION_ID: &str = "c1";
+pub const SESSION_PATH: &str = "/org/freedesktop/login1/session/c1";
+
+/// `org.freedesktop.login1.Manager`, as much of it as d5 uses
+pub struct FakeManager;
+
+#[dbus_interface(name = "org.freedesktop.login1.Manager")]
+impl FakeManager {
+    fn get_session(&self, id: &str) -> fdo::Result<OwnedObjectPath> {
+        if id != SESSION_ID {
+            return Err(fdo::Error::Failed(format!("no session {id}")));
+        }
+        Ok(OwnedObjectPath::try_from(SESSION_PATH).unwrap())
+    }
+
+    /// Powering off needs authentication, as it does with other users logged in
+    fn can_power_off(&self) -> &str {
+        "challenge"
+    }
+
+    fn can_reboot(&self) -> &str {
+        "yes"
+    }
+
+    fn list_inhibitors(&self) -> Vec<(String, String, String, String, u32, u32)> {
+        vec![]
+    }
+
+    #[dbus_interface(property)]
+    fn block_inhibited(&self) -> String {
+        String::new()
+    }
+}
+
+/// `org.freedesktop.login1.Session`, as much of it as d5 uses
+#[derive(Default)]
+pub struct FakeSession {
+    pub idle_hint: bool,
+    /// Microseconds since the epoch
+    pub idle_since_hint: u64,
+    pub locked_hint: bool,
+}
+
+#[dbus_interface(name = "org.freedesktop.login1.Session")]
+impl FakeSession {
+    fn activate(&self) {}
+
+    fn set_locked_hint(&mut self, locked: bool) {
+        self.locked_hint = locked;
+    }
+
+    #[dbus_interface(property)]
+    fn id(&self) -> String {
+        SESSION_ID.to_owned()
+    }
+
+    #[dbus_interface(property)]
+    pub fn idle_hint(&self) -> bool {
+        self.idle_hint
+    }
+
+    #[dbus_interface(property)]
+    pub fn idle_since_hint(&self) -> u64 {
+        self.idle_since_hint
+    }
+
+    #[dbus_interface(property)]
+    fn locked_hint(&self) -> bool {
+        self.locked_hint
+    }
+
+    #[dbus_interface(signal)]
+    pub async fn lock(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+}
+
+/// Serve a logind with a single session on `bus`
+///
+/// Like the real one, it answers on `session/auto` too, but only ever signals on the session's
+/// own path.
+pub async fn serve_logind(bus: &PrivateBus) -> Connection {
+    ConnectionBuilder::address(bus.address())
+        .unwrap()
+        .name("org.freedesktop.login1")
+        .unwrap()
+        .serve_at("/org/freedesktop/login1", FakeManager)
+        .unwrap()
+        .serve_at(
+            "/org/freedesktop/login1/session/auto",
+            FakeSession::default(),
+        )
+        .unwrap()
+        .serve_at(SESSION_PATH, FakeSession::default())
+        .unwrap()
+        .build()
+        .await
+        .unwrap()
+}
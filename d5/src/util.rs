@@ -0,0 +1,47 @@
+//! Small helpers shared between modules
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use zbus::{fdo, MessageHeader};
+
+/// Look up a program like the shell would
+pub fn find_executable(program: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        path.metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|path| is_executable(path))
+    })
+}
+
+/// Unique bus name of whoever sent a method call
+pub fn message_sender(header: &MessageHeader<'_>) -> fdo::Result<String> {
+    header
+        .sender()?
+        .map(|s| s.to_string())
+        .ok_or_else(|| fdo::Error::Failed("message has no sender".to_owned()))
+}
+
+/// Escape a name for use as an object path element, like `sd_bus_path_encode`
+pub fn bus_path_escape(name: &str) -> String {
+    if name.is_empty() {
+        return "_".to_owned();
+    }
+    name.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() {
+                (b as char).to_string()
+            } else {
+                format!("_{b:02x}")
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,113 @@
+//! Wayland leader readiness
+//!
+//! In Wayland sessions, services and autostart entries must not start before the
+//! compositor accepts connections, or they fail to find a display.
+
+use color_eyre::eyre::{bail, eyre};
+use color_eyre::Result;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::UnixStream;
+use tracing::debug;
+
+/// How often to look for the socket
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub fn runtime_dir() -> Result<PathBuf> {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .ok_or_else(|| eyre!("XDG_RUNTIME_DIR is not set"))
+}
+
+/// Wayland sockets in the runtime directory, without their lock files
+fn sockets(dir: &Path) -> HashSet<OsString> {
+    dir.read_dir()
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| e.file_name())
+                .filter(|name| {
+                    let name = name.to_string_lossy();
+                    name.starts_with("wayland-") && !name.ends_with(".lock")
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Where the leader's socket will show up
+pub enum WaylandSocket {
+    /// The socket name d5 gave the leader through `WAYLAND_DISPLAY`
+    Known(String),
+    /// Whatever new socket the leader creates, compared to the ones that were live before it was spawned
+    Learn(HashSet<OsString>),
+}
+
+impl WaylandSocket {
+    /// Prepare for spawning the leader, setting `WAYLAND_DISPLAY` if a socket name is configured
+    ///
+    /// Must be called before the leader is spawned.
+    pub fn prepare(display: Option<&str>) -> Result<Self> {
+        Ok(match display {
+            Some(display) => {
+                std::env::set_var("WAYLAND_DISPLAY", display);
+                Self::Known(display.to_owned())
+            }
+            None => {
+                // the leader must not connect to the compositor d5 itself may be running under
+                std::env::remove_var("WAYLAND_DISPLAY");
+                let dir = runtime_dir()?;
+                // a crashed leader leaves its socket behind, and its replacement may reuse the name
+                let live = sockets(&dir)
+                    .into_iter()
+                    .filter(|s| std::os::unix::net::UnixStream::connect(dir.join(s)).is_ok())
+                    .collect();
+                Self::Learn(live)
+            }
+        })
+    }
+
+    async fn accepts_connections(path: &Path) -> bool {
+        UnixStream::connect(path).await.is_ok()
+    }
+
+    /// Wait until the leader's socket accepts connections, and point `WAYLAND_DISPLAY` at it
+    pub async fn wait(self, timeout: Duration) -> Result<String> {
+        let dir = runtime_dir()?;
+        let wait = async {
+            loop {
+                match &self {
+                    WaylandSocket::Known(display) => {
+                        if Self::accepts_connections(&dir.join(display)).await {
+                            return display.clone();
+                        }
+                    }
+                    WaylandSocket::Learn(before) => {
+                        for socket in sockets(&dir).difference(before) {
+                            if Self::accepts_connections(&dir.join(socket)).await {
+                                return socket.to_string_lossy().into_owned();
+                            }
+                        }
+                    }
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        };
+
+        let Ok(socket) = tokio::time::timeout(timeout, wait).await else {
+            match self {
+                WaylandSocket::Known(display) => {
+                    bail!("the leader did not open {display} within {timeout:?}")
+                }
+                WaylandSocket::Learn(_) => {
+                    bail!("the leader did not open a Wayland socket within {timeout:?}")
+                }
+            }
+        };
+        debug!("Leader is listening on {}", socket);
+        std::env::set_var("WAYLAND_DISPLAY", &socket);
+        Ok(socket)
+    }
+}